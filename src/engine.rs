@@ -0,0 +1,537 @@
+//! Safe interface for embedding the ORT executor from Rust.
+//!
+//! This is a thin layer over the same code as `ort::api`, so limits,
+//! safe points and error reporting behave the same as through the C ABI.
+//!
+//! Values are handed out as `Handle`s, which keep them alive across
+//! collections. An engine only accepts handles it created itself.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use smallvec::SmallVec;
+use hexagon_vm_core::executor::{Executor, ExecutorImpl};
+use hexagon_vm_core::function::Function;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::value::ValueContext;
+use ort::api::{self, InvokeError};
+use ort::bytes::Bytes;
+use ort::exec_state;
+use ort::handles::HandleTable;
+use ort::last_error;
+use host_object::{self, HostObject};
+
+pub use hexagon_vm_core::value::Value;
+pub use ort::handles::Handle;
+pub use ort::interrupt::InterruptHandle;
+pub use ort::last_error::ErrorKind;
+
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new<T: Into<String>>(kind: ErrorKind, message: T) -> Error {
+        Error {
+            kind: kind,
+            message: message.into()
+        }
+    }
+
+    /// Takes the last error recorded by the bridge, falling back to
+    /// `message` if there is none.
+    fn last_or(message: &str) -> Error {
+        match last_error::current() {
+            Some((kind, message)) => Error::new(kind, message),
+            None => Error::new(ErrorKind::Panic, message)
+        }
+    }
+}
+
+fn invoke_error(e: InvokeError) -> Error {
+    Error::new(last_error::kind(), e.message)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Converts Rust values into VM values, allocating objects if needed.
+pub trait IntoValue {
+    fn into_value(self, e: &mut ExecutorImpl) -> Result<Value>;
+}
+
+/// Converts VM values into Rust values.
+pub trait FromValue: Sized {
+    fn from_value(v: Value, e: &ExecutorImpl) -> Result<Self>;
+}
+
+/// An executor together with the bridge-side state attached to it.
+pub struct Engine {
+    executor: Executor
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        Engine {
+            executor: Executor::new()
+        }
+    }
+
+    pub(crate) fn with_executor<R, F: FnOnce(&mut ExecutorImpl) -> R>(&mut self, f: F) -> R {
+        f(&mut *self.executor.handle_mut())
+    }
+
+    /// Decodes a virtual function. `encoding` is `json` or `msgpack`, as
+    /// for `hexagon_ort_function_load_virtual`.
+    pub fn load(&mut self, encoding: &str, code: &[u8]) -> Result<Function> {
+        api::load_virtual(encoding, code).ok_or_else(|| Error::last_or("Unable to load function"))
+    }
+
+    pub fn attach_function(&mut self, key: &str, f: Function) -> Result<()> {
+        self.with_executor(|e| {
+            match catch_unwind(AssertUnwindSafe(|| exec_state::create_static_object(e, key, Box::new(f)))) {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(Error::last_or("Unable to attach function")),
+                Err(p) => {
+                    let (kind, msg) = last_error::describe_panic(p);
+                    Err(Error::new(kind, format!("Unable to attach function: {}", msg)))
+                }
+            }
+        })
+    }
+
+    /// Attaches a closure as a native function under `key`.
+    pub fn register<F>(&mut self, key: &str, f: F) -> Result<()>
+        where F: Fn(&mut CallContext) -> Result<Handle> + 'static {
+        self.attach_function(key, native_function(f))
+    }
+
+    /// Allocates a closure as a native function value.
+    pub fn function<F>(&mut self, f: F) -> Result<Handle>
+        where F: Fn(&mut CallContext) -> Result<Handle> + 'static {
+        self.with_executor(|e| allocate(e, Box::new(native_function(f))).map(|v| HandleTable::root(e, v)))
+    }
+
+    pub fn run(&mut self, key: &str) -> Result<()> {
+        self.with_executor(|e| api::run_callable_value(e, key, None)).map_err(invoke_error)
+    }
+
+    pub fn run_with_timeout(&mut self, key: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        self.with_executor(|e| api::run_callable_value(e, key, Some(deadline))).map_err(invoke_error)
+    }
+
+    /// Invokes `target`, with null as `this` if it is `None`.
+    pub fn invoke(&mut self, target: &Handle, this: Option<&Handle>, args: &[&Handle]) -> Result<Handle> {
+        self.with_executor(|e| invoke_handles(e, target, this, args, None))
+    }
+
+    /// Same as `invoke`, stopping with `Timeout` at the first safe point
+    /// after `timeout`. Scripts are not stopped between safe points.
+    pub fn invoke_with_timeout(
+        &mut self,
+        target: &Handle,
+        this: Option<&Handle>,
+        args: &[&Handle],
+        timeout: Duration
+    ) -> Result<Handle> {
+        let deadline = Instant::now() + timeout;
+        self.with_executor(|e| invoke_handles(e, target, this, args, Some(deadline)))
+    }
+
+    pub fn get_static(&mut self, key: &str) -> Option<Handle> {
+        self.with_executor(|e| {
+            let v: Option<Value> = e.get_static_object(key).map(|v| (*v).into());
+            v.map(|v| HandleTable::root(e, v))
+        })
+    }
+
+    pub fn to_value<T: IntoValue>(&mut self, v: T) -> Result<Handle> {
+        self.with_executor(|e| v.into_value(e).map(|v| HandleTable::root(e, v)))
+    }
+
+    pub fn from_value<T: FromValue>(&mut self, v: &Handle) -> Result<T> {
+        self.with_executor(|e| value_of(e, v).and_then(|v| T::from_value(v, e)))
+    }
+
+    pub fn gc(&mut self) {
+        self.with_executor(|e| e.gc(true));
+    }
+
+    pub fn set_gc_threshold(&mut self, n_objects: usize) {
+        self.with_executor(|e| exec_state::with_mut(e, |s| s.gc_threshold = n_objects));
+    }
+
+    /// Sets an approximate memory limit, enforced at host calls only. See
+    /// `hexagon_ort_executor_impl_set_memory_limit`.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.with_executor(|e| exec_state::with_mut(e, |s| s.memory_limit = bytes));
+    }
+
+    pub fn set_call_budget(&mut self, calls: u64) {
+        self.with_executor(|e| exec_state::with_mut(e, |s| s.call_budget = calls));
+    }
+
+    /// Allocates `obj` as an object proxy.
+    pub fn host_object<T: HostObject>(&mut self, obj: T) -> Result<Handle> {
+        self.with_executor(|e| allocate(e, Box::new(host_object::into_proxy(obj))).map(|v| HandleTable::root(e, v)))
+    }
+
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.with_executor(|e| exec_state::with_mut(e, |s| s.interrupt.clone()))
+    }
+}
+
+/// The call frame seen by a native function registered with `Engine`.
+pub struct CallContext<'a> {
+    executor: &'a mut ExecutorImpl,
+    this: Value,
+    args: SmallVec<[Value; 4]>
+}
+
+impl<'a> CallContext<'a> {
+    /// Enters a native call on `e` and captures `this` and the arguments
+    /// of the current frame. See `exec_state::enter_native`.
+    pub(crate) fn from_frame(e: &'a mut ExecutorImpl) -> CallContext<'a> {
+        let (this, args) = exec_state::enter_native(e);
+
+        CallContext {
+            executor: e,
+            this: this,
+            args: args
+        }
+    }
+
+    pub fn this(&mut self) -> Handle {
+        HandleTable::root(self.executor, self.this)
+    }
+
+    pub fn n_args(&self) -> usize {
+        self.args.len()
+    }
+
+    fn arg_value(&self, index: usize) -> Result<Value> {
+        match self.args.get(index) {
+            Some(v) => Ok(*v),
+            None => Err(Error::new(
+                ErrorKind::InvalidArgument,
+                format!("Argument index out of bound: {}", index)
+            ))
+        }
+    }
+
+    /// Converts the argument at `index`.
+    pub fn arg<T: FromValue>(&self, index: usize) -> Result<T> {
+        self.arg_value(index).and_then(|v| T::from_value(v, &*self.executor))
+    }
+
+    /// Returns the argument at `index` as it is.
+    pub fn arg_handle(&mut self, index: usize) -> Result<Handle> {
+        let v = self.arg_value(index)?;
+        Ok(HandleTable::root(self.executor, v))
+    }
+
+    pub fn to_value<T: IntoValue>(&mut self, v: T) -> Result<Handle> {
+        let v = v.into_value(self.executor)?;
+        Ok(HandleTable::root(self.executor, v))
+    }
+
+    /// Invokes `target` from within the native function.
+    pub fn invoke(&mut self, target: &Handle, this: Option<&Handle>, args: &[&Handle]) -> Result<Handle> {
+        invoke_handles(self.executor, target, this, args, None)
+    }
+}
+
+fn native_function<F>(f: F) -> Function
+    where F: Fn(&mut CallContext) -> Result<Handle> + 'static {
+    Function::from_native(Box::new(move |e: &mut ExecutorImpl| {
+        let ret = f(&mut CallContext::from_frame(e));
+        returned(e, ret, "Native function returns error")
+    }))
+}
+
+/// Returns the value of `h`, which must have been created by `e`.
+fn value_of(e: &ExecutorImpl, h: &Handle) -> Result<Value> {
+    if h.belongs_to(e) {
+        Ok(h.get())
+    } else {
+        Err(Error::new(ErrorKind::InvalidArgument, "Handle belongs to another engine"))
+    }
+}
+
+fn invoke_handles(
+    e: &mut ExecutorImpl,
+    target: &Handle,
+    this: Option<&Handle>,
+    args: &[&Handle],
+    deadline: Option<Instant>
+) -> Result<Handle> {
+    let target = value_of(e, target)?;
+    let this = match this {
+        Some(h) => value_of(e, h)?,
+        None => Value::Null
+    };
+    let args = args.iter().map(|h| value_of(e, h)).collect::<Result<SmallVec<[Value; 4]>>>()?;
+    api::invoke_value(e, target, this, &args, deadline)
+        .map(|v| HandleTable::root(e, v))
+        .map_err(invoke_error)
+}
+
+/// Unwraps what a native callback returned into the value handed back to
+/// the VM, raising its error if it failed.
+pub(crate) fn returned(e: &ExecutorImpl, r: Result<Handle>, context: &str) -> Value {
+    match r.and_then(|h| value_of(e, &h)) {
+        Ok(v) => v,
+        Err(err) => raise(err, context)
+    }
+}
+
+/// Raises `err` as the VM error of a failing native callback, keeping its
+/// kind. A limit hit by the callback's own invocations takes precedence.
+pub(crate) fn raise(err: Error, context: &str) -> ! {
+    last_error::take_thrown();
+    let kind = match err.kind {
+        ErrorKind::None => ErrorKind::NativeError,
+        k => k
+    };
+    let message = if err.message.is_empty() {
+        context.to_string()
+    } else {
+        err.message
+    };
+    match last_error::limit_hit() {
+        Some((limit, limit_msg)) => raise!(limit, "{}", limit_msg),
+        None => raise!(kind, "{}", message)
+    }
+}
+
+fn allocate(e: &mut ExecutorImpl, obj: Box<Object>) -> Result<Value> {
+    allocated(exec_state::allocate(e, obj))
+}
+
+fn allocated(id: Option<usize>) -> Result<Value> {
+    match id {
+        Some(id) => Ok(Value::Object(id)),
+        None => Err(Error::last_or("Unable to allocate object"))
+    }
+}
+
+fn type_mismatch(expected: &str) -> Error {
+    Error::new(ErrorKind::TypeMismatch, format!("Expected {}", expected))
+}
+
+/// Runs `f` on the object behind `v` if it is a `T`.
+fn with_object<T: 'static, R, F: FnOnce(&T) -> R>(v: Value, e: &ExecutorImpl, f: F) -> Option<R> {
+    if !v.is_object() {
+        return None;
+    }
+
+    let ctx = ValueContext::new(&v, e.get_object_pool());
+    let ret = ctx.as_object_direct().as_any().downcast_ref::<T>().map(f);
+    ret
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut ExecutorImpl) -> Result<Value> {
+        Ok(self)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &mut ExecutorImpl) -> Result<Value> {
+        Ok(Value::Null)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut ExecutorImpl) -> Result<Value> {
+        Ok(Value::Bool(self))
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self, _: &mut ExecutorImpl) -> Result<Value> {
+        Ok(Value::Int(self))
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut ExecutorImpl) -> Result<Value> {
+        Ok(Value::Float(self))
+    }
+}
+
+impl<'a> IntoValue for &'a str {
+    fn into_value(self, e: &mut ExecutorImpl) -> Result<Value> {
+        self.to_string().into_value(e)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, e: &mut ExecutorImpl) -> Result<Value> {
+        let len = self.len();
+        allocated(exec_state::allocate_sized(e, Box::new(self), len))
+    }
+}
+
+impl IntoValue for Vec<u8> {
+    fn into_value(self, e: &mut ExecutorImpl) -> Result<Value> {
+        allocated(exec_state::allocate_bytes(e, self))
+    }
+}
+
+impl FromValue for () {
+    fn from_value(v: Value, _: &ExecutorImpl) -> Result<()> {
+        match v {
+            Value::Null => Ok(()),
+            _ => Err(type_mismatch("null"))
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(v: Value, _: &ExecutorImpl) -> Result<bool> {
+        match v {
+            Value::Bool(v) => Ok(v),
+            _ => Err(type_mismatch("bool"))
+        }
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(v: Value, _: &ExecutorImpl) -> Result<i64> {
+        match v {
+            Value::Int(v) => Ok(v),
+            _ => Err(type_mismatch("int"))
+        }
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(v: Value, _: &ExecutorImpl) -> Result<f64> {
+        match v {
+            Value::Float(v) => Ok(v),
+            Value::Int(v) => Ok(v as f64),
+            _ => Err(type_mismatch("float"))
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(v: Value, e: &ExecutorImpl) -> Result<String> {
+        with_object(v, e, |s: &String| s.clone()).ok_or_else(|| type_mismatch("string"))
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(v: Value, e: &ExecutorImpl) -> Result<Vec<u8>> {
+        with_object(v, e, |b: &Bytes| b.data.clone()).ok_or_else(|| type_mismatch("bytes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closures_as_native_functions() {
+        let mut engine = Engine::new();
+
+        let add = engine.function(|ctx| {
+            let a: i64 = ctx.arg(0)?;
+            let b: i64 = ctx.arg(1)?;
+            ctx.to_value(a + b)
+        }).unwrap();
+        let one = engine.to_value(1i64).unwrap();
+        let two = engine.to_value(2i64).unwrap();
+        let ret = engine.invoke(&add, None, &[&one, &two]).unwrap();
+        assert_eq!(engine.from_value::<i64>(&ret).unwrap(), 3);
+
+        let fail = engine.function(|_| {
+            Err(Error::new(ErrorKind::InvalidArgument, "bad input"))
+        }).unwrap();
+        let err = engine.invoke(&fail, None, &[]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidArgument);
+        assert_eq!(err.message, "bad input");
+
+        let s = engine.to_value("hello").unwrap();
+        assert_eq!(engine.from_value::<String>(&s).unwrap(), "hello");
+    }
+
+    #[test]
+    fn returned_values_survive_collection() {
+        let mut engine = Engine::new();
+        engine.set_gc_threshold(1);
+
+        let concat = engine.function(|ctx| {
+            let a: String = ctx.arg(0)?;
+            let b: String = ctx.arg(1)?;
+            ctx.to_value(a + &b)
+        }).unwrap();
+        let a = engine.to_value("foo").unwrap();
+        let b = engine.to_value("bar").unwrap();
+
+        let ret = engine.invoke(&concat, None, &[&a, &b]).unwrap();
+        engine.gc();
+        let again = engine.invoke(&concat, None, &[&ret, &b]).unwrap();
+        assert_eq!(engine.from_value::<String>(&again).unwrap(), "foobarbar");
+    }
+
+    #[test]
+    fn nested_invocations_from_native_functions() {
+        let mut engine = Engine::new();
+
+        let double = engine.function(|ctx| {
+            let n: i64 = ctx.arg(0)?;
+            ctx.to_value(n * 2)
+        }).unwrap();
+        let quadruple = engine.function(|ctx| {
+            let target = ctx.arg_handle(0)?;
+            let n = ctx.arg_handle(1)?;
+            let twice = ctx.invoke(&target, None, &[&n])?;
+            ctx.invoke(&target, None, &[&twice])
+        }).unwrap();
+
+        let three = engine.to_value(3i64).unwrap();
+        let ret = engine.invoke(&quadruple, None, &[&double, &three]).unwrap();
+        assert_eq!(engine.from_value::<i64>(&ret).unwrap(), 12);
+    }
+
+    #[test]
+    fn handles_are_tied_to_their_engine() {
+        let mut a = Engine::new();
+        let mut b = Engine::new();
+
+        let f = a.function(|ctx| ctx.to_value(())).unwrap();
+        let n = b.to_value(1i64).unwrap();
+        assert_eq!(b.invoke(&f, None, &[]).unwrap_err().kind, ErrorKind::InvalidArgument);
+        assert_eq!(a.invoke(&f, None, &[&n]).unwrap_err().kind, ErrorKind::InvalidArgument);
+        assert_eq!(a.from_value::<i64>(&n).unwrap_err().kind, ErrorKind::InvalidArgument);
+        assert!(a.invoke(&f, None, &[]).is_ok());
+    }
+
+    #[test]
+    fn attaching_functions_respects_the_memory_limit() {
+        let mut engine = Engine::new();
+        engine.set_memory_limit(1);
+
+        let f = native_function(|ctx| ctx.to_value(()));
+        let err = engine.attach_function("f", f).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfMemory);
+        assert!(engine.get_static("f").is_none());
+        last_error::clear();
+    }
+}
@@ -0,0 +1,450 @@
+//! Host objects implemented in Rust.
+//!
+//! `into_proxy` builds an `ObjectProxy` whose hooks forward to a
+//! `HostObject`, so host objects get the same static, const and frozen
+//! field handling as proxies set up through the C ABI. A panic in a
+//! `HostObject` method is caught and raised in the VM as an error.
+
+use std::os::raw::c_char;
+use std::ffi::{CStr, CString};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::value::Value;
+use glue::hexagon_glue_alloc;
+use ort::object_proxy::{FieldVisitor, ObjectProxy, TraceVisitor};
+use ort::last_error;
+use engine::{self, CallContext, Error, ErrorKind, Handle, Result};
+
+/// A Rust value exposed to scripts as an object.
+///
+/// Every method has a default that fails the same way an `ObjectProxy`
+/// without the matching hook does. Errors are raised in the VM with
+/// their message.
+pub trait HostObject: 'static {
+    fn call(&self, _ctx: &mut CallContext) -> Result<Handle> {
+        Err(Error::new(ErrorKind::Unsupported, "Not callable"))
+    }
+
+    /// Called for fields that are not static fields of the proxy. `None`
+    /// means the field does not exist.
+    fn get_field(&self, _name: &str) -> Result<Option<Value>> {
+        Ok(None)
+    }
+
+    /// Called for fields that are not const. Values are not rooted by the
+    /// proxy; report the ones kept in `self` from `trace`.
+    fn set_field(&self, _name: &str, _value: Value) -> Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "Not implemented"))
+    }
+
+    /// Whether `name` is a field of this object. Static fields of the proxy
+    /// are not asked about. Defaults to whether `get_field` finds it.
+    fn has_field(&self, name: &str) -> Result<bool> {
+        self.get_field(name).map(|v| v.is_some())
+    }
+
+    /// Called for fields that are not static fields of the proxy.
+    fn delete_field(&self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Names of the fields of this object, merged with the static fields
+    /// of the proxy when it is enumerated.
+    fn field_names(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Calls `visit` on each value kept alive by this object, so that the
+    /// collector does not free them. Runs during collection and must not
+    /// touch the executor.
+    fn trace(&self, _visit: &mut FnMut(Value)) {}
+
+    /// Queried once, when the proxy is built.
+    fn typename(&self) -> &str {
+        "host_object"
+    }
+
+    /// Queried once, when the proxy is built.
+    fn const_fields(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Queried once, when the proxy is built. Frozen objects reject
+    /// every field assignment.
+    fn frozen(&self) -> bool {
+        false
+    }
+
+    fn to_i64(&self) -> Result<i64> {
+        Err(Error::new(ErrorKind::Unsupported, "Cannot convert proxied object to i64"))
+    }
+
+    fn to_f64(&self) -> Result<f64> {
+        Err(Error::new(ErrorKind::Unsupported, "Cannot convert proxied object to f64"))
+    }
+
+    fn to_bool(&self) -> Result<bool> {
+        Err(Error::new(ErrorKind::Unsupported, "Cannot convert proxied object to bool"))
+    }
+
+    fn to_string(&self) -> Result<String> {
+        Err(Error::new(ErrorKind::Unsupported, "Cannot convert proxied object to string"))
+    }
+
+    /// Queried once, when the proxy is built. Objects that are not
+    /// comparable cannot be ordered and are only equal to themselves.
+    fn comparable(&self) -> bool {
+        false
+    }
+
+    /// Orders this object, on the left, against `other`. Only called if
+    /// `comparable` returns true.
+    fn compare(&self, _other: Value) -> Result<Ordering> {
+        Err(Error::new(ErrorKind::Unsupported, "Not comparable"))
+    }
+}
+
+struct HostData<T: HostObject> {
+    obj: T,
+    typename: CString
+}
+
+/// Wraps `obj` into a proxy that owns it.
+pub fn into_proxy<T: HostObject>(obj: T) -> ObjectProxy {
+    let typename = CString::new(obj.typename())
+        .unwrap_or_else(|_| CString::new("host_object").unwrap());
+    let frozen = obj.frozen();
+    let comparable = obj.comparable();
+    let const_fields: HashSet<String> = obj.const_fields().into_iter().collect();
+
+    let data = Box::into_raw(Box::new(HostData {
+        obj: obj,
+        typename: typename
+    })) as *const ();
+
+    let mut p = ObjectProxy::new(data);
+    p.frozen = frozen;
+    p.const_fields = const_fields;
+    p.destructor = Some(destroy::<T>);
+    p.host_call = Some(Box::new(move |e: &mut ExecutorImpl| {
+        let ret = guarded(|| host::<T>(data).obj.call(&mut CallContext::from_frame(e)));
+        engine::returned(e, ret, "Proxied object returns error")
+    }));
+    p.host_get_field = Some(Box::new(move |name: &str| {
+        match guarded(|| host::<T>(data).obj.get_field(name)) {
+            Ok(v) => v,
+            Err(err) => engine::raise(err, "Proxied object returns error")
+        }
+    }));
+    p.on_set_field = Some(set_field::<T>);
+    p.on_has_field = Some(has_field::<T>);
+    p.on_delete_field = Some(delete_field::<T>);
+    p.on_enumerate_fields = Some(enumerate_fields::<T>);
+    p.on_typename = Some(typename::<T>);
+    p.on_to_i64 = Some(to_i64::<T>);
+    p.on_to_f64 = Some(to_f64::<T>);
+    p.on_to_bool = Some(to_bool::<T>);
+    p.on_to_string = Some(to_string::<T>);
+    p.on_trace = Some(trace::<T>);
+    if comparable {
+        p.on_compare = Some(compare::<T>);
+    }
+    p
+}
+
+fn host<'a, T: HostObject>(data: *const ()) -> &'a HostData<T> {
+    unsafe { &*(data as *const HostData<T>) }
+}
+
+/// Runs a `HostObject` method, turning a panic into an error.
+fn guarded<R, F: FnOnce() -> Result<R>>(f: F) -> Result<R> {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(p) => {
+            let (kind, msg) = last_error::describe_panic(p);
+            Err(Error::new(kind, msg))
+        }
+    }
+}
+
+/// Writes the result of a hook, or stores its error message to be
+/// raised by the proxy.
+fn finish<R>(ret_place: *mut R, r: Result<R>) -> i32 {
+    match r {
+        Ok(v) => {
+            unsafe { ::std::ptr::write(ret_place, v); }
+            0
+        },
+        Err(e) => {
+            last_error::set_thrown(e.message);
+            1
+        }
+    }
+}
+
+extern "C" fn destroy<T: HostObject>(data: *const ()) {
+    // There is nobody to report a panicking destructor to.
+    let _ = catch_unwind(AssertUnwindSafe(|| unsafe { Box::from_raw(data as *mut HostData<T>); }));
+}
+
+extern "C" fn set_field<T: HostObject>(data: *const (), field_name: *const c_char, value: *const Value) -> i32 {
+    let name = unsafe { CStr::from_ptr(field_name) }.to_string_lossy();
+    let mut ret = ();
+    finish(&mut ret, guarded(|| host::<T>(data).obj.set_field(&name, unsafe { *value })))
+}
+
+extern "C" fn has_field<T: HostObject>(ret_place: *mut u32, data: *const (), field_name: *const c_char) -> i32 {
+    let name = unsafe { CStr::from_ptr(field_name) }.to_string_lossy();
+    finish(ret_place, guarded(|| host::<T>(data).obj.has_field(&name)).map(|v| if v { 1 } else { 0 }))
+}
+
+extern "C" fn delete_field<T: HostObject>(data: *const (), field_name: *const c_char) -> i32 {
+    let name = unsafe { CStr::from_ptr(field_name) }.to_string_lossy();
+    let mut ret = ();
+    finish(&mut ret, guarded(|| host::<T>(data).obj.delete_field(&name)))
+}
+
+extern "C" fn enumerate_fields<T: HostObject>(data: *const (), visit: FieldVisitor, visit_ctx: *const ()) -> i32 {
+    let names = match guarded(|| host::<T>(data).obj.field_names()) {
+        Ok(v) => v,
+        Err(e) => {
+            last_error::set_thrown(e.message);
+            return 1;
+        }
+    };
+    for name in names.iter() {
+        if visit(name.as_ptr(), name.len() as u32, visit_ctx) != 0 {
+            break;
+        }
+    }
+    0
+}
+
+extern "C" fn trace<T: HostObject>(data: *const (), visit: TraceVisitor, visit_ctx: *const ()) {
+    // A panic here cannot be raised in the middle of a collection.
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        host::<T>(data).obj.trace(&mut |v: Value| visit(visit_ctx, &v))
+    }));
+}
+
+extern "C" fn typename<T: HostObject>(data: *const ()) -> *const c_char {
+    host::<T>(data).typename.as_ptr()
+}
+
+extern "C" fn to_i64<T: HostObject>(ret_place: *mut i64, data: *const ()) -> i32 {
+    finish(ret_place, guarded(|| host::<T>(data).obj.to_i64()))
+}
+
+extern "C" fn to_f64<T: HostObject>(ret_place: *mut f64, data: *const ()) -> i32 {
+    finish(ret_place, guarded(|| host::<T>(data).obj.to_f64()))
+}
+
+extern "C" fn to_bool<T: HostObject>(ret_place: *mut u32, data: *const ()) -> i32 {
+    finish(ret_place, guarded(|| host::<T>(data).obj.to_bool()).map(|v| if v { 1 } else { 0 }))
+}
+
+extern "C" fn to_string<T: HostObject>(data: *const ()) -> *mut c_char {
+    let s = match guarded(|| host::<T>(data).obj.to_string()) {
+        Ok(v) => v,
+        Err(e) => {
+            last_error::set_thrown(e.message);
+            return ::std::ptr::null_mut();
+        }
+    };
+    let s = CString::new(s).unwrap_or_else(|e| {
+        let mut s = e.into_vec();
+        s.retain(|&c| c != 0);
+        CString::new(s).unwrap()
+    });
+    let bytes = s.as_bytes_with_nul();
+    unsafe {
+        let buf = hexagon_glue_alloc(bytes.len());
+        ::std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf, bytes.len());
+        buf as *mut c_char
+    }
+}
+
+extern "C" fn compare<T: HostObject>(ret_place: *mut i32, data: *const (), other: *const Value) -> i32 {
+    finish(ret_place, guarded(|| host::<T>(data).obj.compare(unsafe { *other })).map(|o| match o {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use hexagon_vm_core::value::{Value, ValueContext};
+    use engine::{Engine, Error, Handle, Result};
+    use ort::last_error::{self, ErrorKind};
+    use ort::api::{hexagon_ort_object_proxy_enumerate_fields, hexagon_ort_object_proxy_has_field};
+    use ort::test_util::{c_str, pin_counted_proxy};
+    use super::HostObject;
+
+    struct Counter;
+
+    impl HostObject for Counter {
+        fn get_field(&self, name: &str) -> Result<Option<Value>> {
+            match name {
+                "count" => Ok(Some(Value::Int(1))),
+                "broken" => panic!("broken field"),
+                _ => Ok(None)
+            }
+        }
+
+        fn field_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["count".to_string()])
+        }
+
+        fn to_i64(&self) -> Result<i64> {
+            panic!("broken conversion")
+        }
+    }
+
+    fn get_field(engine: &mut Engine, obj: &Handle, name: &str) -> ::std::thread::Result<Option<Value>> {
+        let v = obj.get();
+        engine.with_executor(|e| catch_unwind(AssertUnwindSafe(|| {
+            let pool = e.get_object_pool();
+            ValueContext::new(&v, pool).as_object_direct().get_field(pool, name)
+        })))
+    }
+
+    #[test]
+    fn missing_fields_are_reported_as_missing() {
+        let mut engine = Engine::new();
+        let obj = engine.host_object(Counter).unwrap();
+
+        match get_field(&mut engine, &obj, "count").unwrap() {
+            Some(Value::Int(1)) => {},
+            _ => panic!("expected the field value")
+        }
+        assert!(get_field(&mut engine, &obj, "missing").unwrap().is_none());
+    }
+
+    extern "C" fn collect_name(name: *const u8, len: u32, user_data: *const ()) -> i32 {
+        let names = unsafe { &mut *(user_data as *mut Vec<String>) };
+        let name = unsafe { ::std::slice::from_raw_parts(name, len as usize) };
+        names.push(String::from_utf8(name.to_vec()).unwrap());
+        0
+    }
+
+    #[test]
+    fn fields_can_be_tested_and_enumerated() {
+        let mut engine = Engine::new();
+        let obj = engine.host_object(Counter).unwrap();
+
+        let v = obj.get();
+        engine.with_executor(|e| {
+            let mut has = 0;
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"count\0")), 0);
+            assert_eq!(has, 1);
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"missing\0")), 0);
+            assert_eq!(has, 0);
+
+            let mut names: Vec<String> = Vec::new();
+            let ret = hexagon_ort_object_proxy_enumerate_fields(&v, e, collect_name, &mut names as *mut Vec<String> as *const ());
+            assert_eq!(ret, 0);
+            assert_eq!(names, vec!["count".to_string()]);
+        });
+    }
+
+    #[test]
+    fn panics_are_raised_as_vm_errors() {
+        let mut engine = Engine::new();
+        let obj = engine.host_object(Counter).unwrap();
+
+        let payload = get_field(&mut engine, &obj, "broken").unwrap_err();
+        let (kind, msg) = last_error::describe_panic(payload);
+        assert_eq!(kind, ErrorKind::VMError);
+        assert!(msg.contains("broken field"));
+
+        let v = obj.get();
+        let payload = engine.with_executor(|e| catch_unwind(AssertUnwindSafe(|| {
+            ValueContext::new(&v, e.get_object_pool()).as_object_direct().to_i64()
+        }))).unwrap_err();
+        let (kind, msg) = last_error::describe_panic(payload);
+        assert_eq!(kind, ErrorKind::VMError);
+        assert!(msg.contains("broken conversion"));
+        last_error::clear();
+    }
+
+    struct Version(i64);
+
+    impl HostObject for Version {
+        fn comparable(&self) -> bool {
+            true
+        }
+
+        fn compare(&self, other: Value) -> Result<::std::cmp::Ordering> {
+            match other {
+                Value::Int(n) => Ok(self.0.cmp(&n)),
+                _ => Err(Error::new(ErrorKind::TypeMismatch, "Expected int"))
+            }
+        }
+    }
+
+    #[test]
+    fn comparable_objects_reach_compare() {
+        let mut engine = Engine::new();
+        let version = engine.host_object(Version(2)).unwrap();
+        let counter = engine.host_object(Counter).unwrap();
+
+        let (v, c) = (version.get(), counter.get());
+        engine.with_executor(|e| {
+            let pool = e.get_object_pool();
+            let version = ValueContext::new(&v, pool).as_object_direct();
+            assert_eq!(version.compare(&ValueContext::new(&Value::Int(3), pool)), Some(::std::cmp::Ordering::Less));
+            assert!(version.test_eq(&ValueContext::new(&Value::Int(2), pool)));
+
+            // Not comparable: only equal to itself.
+            let counter = ValueContext::new(&c, pool).as_object_direct();
+            assert!(counter.test_eq(&ValueContext::new(&c, pool)));
+            assert!(!counter.test_eq(&ValueContext::new(&v, pool)));
+        });
+    }
+
+    struct Holder {
+        slot: Cell<Value>
+    }
+
+    impl HostObject for Holder {
+        fn set_field(&self, _name: &str, value: Value) -> Result<()> {
+            self.slot.set(value);
+            Ok(())
+        }
+
+        fn trace(&self, visit: &mut FnMut(Value)) {
+            visit(self.slot.get());
+        }
+    }
+
+    static HELD_DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    #[test]
+    fn traced_values_survive_collection() {
+        let mut engine = Engine::new();
+        let holder = engine.host_object(Holder { slot: Cell::new(Value::Null) }).unwrap();
+
+        let held = engine.with_executor(|e| pin_counted_proxy(e, &HELD_DROPS));
+        let v = holder.get();
+        engine.with_executor(|e| {
+            let pool = e.get_object_pool();
+            ValueContext::new(&v, pool).as_object_direct().set_field("held", held);
+        });
+
+        engine.gc();
+        assert_eq!(HELD_DROPS.load(Ordering::SeqCst), 0);
+
+        engine.with_executor(|e| {
+            let pool = e.get_object_pool();
+            ValueContext::new(&v, pool).as_object_direct().set_field("held", Value::Null);
+        });
+        engine.gc();
+        assert_eq!(HELD_DROPS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -6,6 +6,11 @@ use hexagon_vm_core::hybrid::program_context::{
 };
 use super::provider::{InvokeCallback, GenericJitProvider};
 use std::ptr;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use ort::api::ffi_guard;
+use ort::last_error;
+use ort::last_error::ErrorKind;
+use ort::interrupt::InterruptHandle;
 use hexagon_vm_core::hybrid::program::{Program, ProgramInfo};
 
 #[cfg(feature = "active_import")]
@@ -16,7 +21,7 @@ extern "C" {
 
 #[no_mangle]
 pub extern "C" fn hexagon_hybrid_executor_create() -> *mut Executor {
-    Box::into_raw(Box::new(Executor::new()))
+    ffi_guard(ptr::null_mut(), || Box::into_raw(Box::new(Executor::new())))
 }
 
 #[no_mangle]
@@ -34,6 +39,8 @@ unsafe extern "C" fn call_global_invoke(_: *const ContextHandle, _: u32, _: usiz
     panic!("call_global_invoke called with active_import disabled");
 }
 
+/// Returns null and records the reason in the last error if `code` cannot
+/// be decoded or verified.
 #[no_mangle]
 pub extern "C" fn hexagon_hybrid_executor_load_program<'a>(
     e: &'a Executor,
@@ -47,31 +54,60 @@ pub extern "C" fn hexagon_hybrid_executor_load_program<'a>(
     } else {
         call_global_invoke
     };
-    let code = unsafe {
-        ::std::slice::from_raw_parts(code, len as usize)
-    };
-    let program_info = match ProgramInfo::std_deserialize(code) {
-        Some(v) => v,
-        None => return ptr::null_mut()
-    };
-    let program = match Program::load(program_info, |_| None) {
-        Some(v) => v,
-        None => return ptr::null_mut()
+    if code.is_null() && len != 0 {
+        set_last_error!(ErrorKind::InvalidArgument, "Null code buffer with length {}", len);
+        return ptr::null_mut();
+    }
+    let code: &[u8] = if len == 0 {
+        &[]
+    } else {
+        unsafe { ::std::slice::from_raw_parts(code, len as usize) }
     };
 
-    let ctx = ProgramContext::new(
-        e,
-        program,
-        Some(GenericJitProvider {
-            on_fn_invoke: on_fn_invoke,
-            user_data: user_data
-        })
-    );
-    let owner = ContextOwner {
-        context: ctx
+    let program = match catch_unwind(AssertUnwindSafe(|| load_program(code))) {
+        Ok(Some(v)) => v,
+        Ok(None) => return ptr::null_mut(),
+        Err(e) => {
+            set_last_error_from_panic!(e, "Unable to load program");
+            return ptr::null_mut();
+        }
     };
 
-    Box::into_raw(Box::new(owner))
+    ffi_guard(ptr::null_mut(), || {
+        let interrupt = InterruptHandle::new();
+        let ctx = ProgramContext::new(
+            e,
+            program,
+            Some(GenericJitProvider {
+                on_fn_invoke: on_fn_invoke,
+                user_data: user_data,
+                interrupt: interrupt.clone()
+            })
+        );
+        let owner = ContextOwner {
+            context: ctx,
+            interrupt: interrupt
+        };
+
+        Box::into_raw(Box::new(owner))
+    })
+}
+
+fn load_program(code: &[u8]) -> Option<Program> {
+    let program_info = match ProgramInfo::std_deserialize(code) {
+        Some(v) => v,
+        None => {
+            set_last_error!(ErrorKind::Decode, "Unable to deserialize program");
+            return None;
+        }
+    };
+    match Program::load(program_info, |_| None) {
+        Some(v) => Some(v),
+        None => {
+            set_last_error!(ErrorKind::Verification, "Unable to load program");
+            None
+        }
+    }
 }
 
 #[no_mangle]
@@ -81,26 +117,64 @@ pub unsafe extern "C" fn hexagon_hybrid_context_destroy<'a>(
     Box::from_raw(ctx);
 }
 
+/// Returns 0 on success, otherwise 1 with the reason in the last error.
+///
+/// ABI note: this used to return nothing. Callers that still declare it
+/// as returning `void` keep working, since the result is passed in a
+/// register, but do not see failures.
 #[no_mangle]
 pub unsafe extern "C" fn hexagon_hybrid_context_run(
     ctx: &ContextOwner
-) {
-    ctx.context.get_executor().eval_program(&ctx.context, 0);
+) -> i32 {
+    last_error::begin_invocation();
+    ctx.interrupt.reset();
+
+    match catch_unwind(AssertUnwindSafe(
+        || ctx.context.get_executor().eval_program(&ctx.context, 0)
+    )) {
+        Ok(_) => 0,
+        Err(e) => {
+            let (kind, msg) = last_error::describe_panic(e);
+            let kind = last_error::take_raised(&msg).unwrap_or(kind);
+            set_last_error!(kind, "Program execution failed: {}", msg);
+            1
+        }
+    }
 }
 
+/// Returns a handle that interrupts `hexagon_hybrid_context_run` the next
+/// time the program invokes a host function. The flag is checked there
+/// only: the compiled program itself never polls it, so a program that
+/// does not call the host runs to completion. Release the handle with
+/// `hexagon_ort_interrupt_handle_destroy`.
+#[no_mangle]
+pub extern "C" fn hexagon_hybrid_context_create_interrupt_handle(
+    ctx: &ContextOwner
+) -> *mut InterruptHandle {
+    Box::into_raw(Box::new(ctx.interrupt.clone()))
+}
+
+/// Returns 0 on success, otherwise 1 with the reason in the last error.
+///
+/// ABI note: like `hexagon_hybrid_context_run`, this used to return nothing.
 #[no_mangle]
 pub unsafe extern "C" fn hexagon_hybrid_context_set_global(
     ctx: &ContextOwner,
     id: u32,
     value: u32
-) {
-    ctx.context.get_executor().write_global(id as usize, value as u64);
+) -> i32 {
+    ffi_guard(1, || {
+        ctx.context.get_executor().write_global(id as usize, value as u64);
+        0
+    })
 }
 
+/// Returns 0 and records the reason in the last error if `id` cannot be
+/// read. Clear the last error first to tell that apart from a zero value.
 #[no_mangle]
 pub unsafe extern "C" fn hexagon_hybrid_context_get_global(
     ctx: &ContextOwner,
     id: u32
 ) -> u32 {
-    ctx.context.get_executor().read_global(id as usize) as u32
+    ffi_guard(0, || ctx.context.get_executor().read_global(id as usize) as u32)
 }
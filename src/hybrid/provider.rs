@@ -1,11 +1,14 @@
 use hexagon_vm_core::hybrid::jit::JitProvider;
+use ort::interrupt::InterruptHandle;
+use ort::last_error::ErrorKind;
 use hexagon_vm_core::hybrid::program_context::{
     ProgramContext,
     CommonProgramContext
 };
 
 pub struct ContextOwner<'a> {
-    pub(crate) context: ProgramContext<'a, GenericJitProvider>
+    pub(crate) context: ProgramContext<'a, GenericJitProvider>,
+    pub(crate) interrupt: InterruptHandle
 }
 
 pub struct ContextHandle<'a> {
@@ -15,11 +18,16 @@ pub struct ContextHandle<'a> {
 pub type InvokeCallback = unsafe extern "C" fn (handle: *const ContextHandle, fn_id: u32, user_data: usize) -> i32;
 pub struct GenericJitProvider {
     pub(crate) on_fn_invoke: InvokeCallback,
-    pub(crate) user_data: usize
+    pub(crate) user_data: usize,
+    pub(crate) interrupt: InterruptHandle
 }
 
 impl JitProvider for GenericJitProvider {
     fn invoke_function(&self, ctx: &CommonProgramContext, id: usize) -> bool {
+        if self.interrupt.take() {
+            raise!(ErrorKind::Interrupted, "Interrupted");
+        }
+
         let ctx_handle = ContextHandle {
             _context: ctx
         };
@@ -1,8 +1,12 @@
 extern crate hexagon_vm_core;
+extern crate serde;
 extern crate serde_json;
 extern crate rmp_serde;
 extern crate smallvec;
 
 pub mod glue;
-pub mod hybrid;
+#[macro_use]
 pub mod ort;
+pub mod hybrid;
+pub mod engine;
+pub mod host_object;
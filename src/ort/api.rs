@@ -1,15 +1,44 @@
+//! C interface of the ORT bridge.
+//!
+//! Exports that can fail return a status, 0 meaning success, and record
+//! the reason in the last error (see `hexagon_ort_get_last_error`).
+//!
+//! ABI note: `hexagon_ort_executor_impl_get_static_object`,
+//! `hexagon_ort_executor_pin_object_proxy`, `hexagon_ort_executor_pin_function`,
+//! `hexagon_ort_object_proxy_add_const_field`, `hexagon_ort_object_proxy_set_static_field`
+//! and `hexagon_ort_value_create_from_string` used to return nothing and now
+//! return an `i32` status. Hosts declaring them as `void` still link and run,
+//! but cannot see failures and should be updated.
+
 use std::os::raw::c_char;
 use std::ffi::{CStr, CString};
 use std::ptr::{null, null_mut};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 use std::panic::{AssertUnwindSafe, catch_unwind};
+use smallvec::SmallVec;
 use hexagon_vm_core::executor::{Executor, ExecutorImpl};
 use hexagon_vm_core::value::{Value, ValueContext};
 use hexagon_vm_core::object_info::ObjectHandle;
+use hexagon_vm_core::object::Object;
 use hexagon_vm_core::function::Function;
 use hexagon_vm_core::function::VirtualFunctionInfo;
 use hexagon_vm_core::errors::VMError;
 use super::object_proxy;
 use super::object_proxy::ObjectProxy;
+use super::bytes::Bytes;
+use super::collections::{Array, Map};
+use super::serialize::{self, SerializeValue, DeserializeValue};
+use super::handles::{Handle, HandleTable};
+use super::exec_state;
+use super::exec_state::HeapStats;
+use super::interrupt::InterruptHandle;
+use super::signature::Signature;
+use super::methods;
+use super::last_error;
+use super::last_error::{ErrorKind, LastErrorInfo};
+use glue::{hexagon_glue_alloc, hexagon_glue_free};
 
 use rmp_serde;
 use serde_json;
@@ -19,6 +48,29 @@ pub unsafe extern "C" fn hexagon_enable_debug() {
     ::hexagon_vm_core::debug::enable();
 }
 
+/// Fills `ret_place` with the last error recorded on the current thread.
+///
+/// Returns 0 if there is one, otherwise 1.
+///
+/// The record is written only by calls that fail and is never cleared
+/// implicitly, so it is meaningful only after a call has reported a
+/// failure through its return value. Queries such as
+/// `hexagon_ort_value_read_null` answering "no" are not failures.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_get_last_error(ret_place: &mut LastErrorInfo) -> i32 {
+    if last_error::get(ret_place) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Clears the last error. This is the only way it is ever cleared.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_clear_last_error() {
+    last_error::clear();
+}
+
 #[no_mangle]
 pub extern "C" fn hexagon_ort_get_value_size() -> u32 {
     ::std::mem::size_of::<Value>() as u32
@@ -26,31 +78,44 @@ pub extern "C" fn hexagon_ort_get_value_size() -> u32 {
 
 #[no_mangle]
 pub extern "C" fn hexagon_ort_executor_create() -> *mut Executor {
-    Box::into_raw(Box::new(Executor::new()))
+    ffi_guard(null_mut(), || Box::into_raw(Box::new(Executor::new())))
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn hexagon_ort_executor_destroy(e: *mut Executor) {
-    Box::from_raw(e);
+    ffi_guard((), || {
+        Box::from_raw(e);
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn hexagon_ort_executor_get_impl(e: &mut Executor) -> *mut ExecutorImpl {
-    &mut *e.handle_mut() as *mut ExecutorImpl
+    ffi_guard(null_mut(), || &mut *e.handle_mut() as *mut ExecutorImpl)
 }
 
+/// Attaches `f` as the static object `key`. Returns 0 on success.
+///
+/// Ownership of `f` is always taken, even when this fails because `key`
+/// is not a valid string, so the host must not destroy it afterwards.
 #[no_mangle]
 pub extern "C" fn hexagon_ort_executor_impl_attach_function(
     e: &mut ExecutorImpl,
     key: *const c_char,
     f: *mut Function
 ) -> u32 {
-    let key = unsafe { CStr::from_ptr(key).to_str().unwrap() };
     let f = unsafe { Box::from_raw(f) };
+    let key = match unsafe { read_c_str(key) } {
+        Some(v) => v,
+        None => return 1
+    };
 
-    match catch_unwind(AssertUnwindSafe(|| e.create_static_object(key, f))) {
-        Ok(_) => 0,
-        Err(_) => 1
+    match catch_unwind(AssertUnwindSafe(|| exec_state::create_static_object(e, key, f))) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            set_last_error_from_panic!(e, "Unable to attach function");
+            1
+        }
     }
 }
 
@@ -59,13 +124,56 @@ pub extern "C" fn hexagon_ort_executor_impl_run_callable(
     e: &mut ExecutorImpl,
     key: *const c_char
 ) -> u32 {
-    let key = unsafe { CStr::from_ptr(key).to_str().unwrap() };
-    match catch_unwind(AssertUnwindSafe(|| e.run_callable(key))) {
+    let key = match unsafe { read_c_str(key) } {
+        Some(v) => v,
+        None => return 1
+    };
+    match run_callable_value(e, key, None) {
         Ok(_) => 0,
         Err(_) => 1
     }
 }
 
+/// Same as `hexagon_ort_executor_impl_invoke_checked`, but runs
+/// a static callable.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_run_callable_checked(
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    key: *const c_char
+) -> u32 {
+    run_callable_checked(err_place, e, key, None)
+}
+
+/// Same as `hexagon_ort_executor_impl_run_callable_checked`, but stops
+/// with `Timeout` at the first safe point after `timeout_ms` milliseconds.
+/// See `hexagon_ort_executor_impl_invoke_with_timeout` for what that covers.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_run_callable_with_timeout(
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    key: *const c_char,
+    timeout_ms: u32
+) -> u32 {
+    run_callable_checked(err_place, e, key, Some(deadline_after(timeout_ms)))
+}
+
+fn run_callable_checked(
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    key: *const c_char,
+    deadline: Option<Instant>
+) -> u32 {
+    let key = match unsafe { read_c_str(key) } {
+        Some(v) => v,
+        None => return InvokeStatus::InvalidArgument as u32
+    };
+    match run_callable_value(e, key, deadline) {
+        Ok(_) => InvokeStatus::Ok as u32,
+        Err(err) => write_invoke_error(err_place, err)
+    }
+}
+
 // this is actually unsafe but since we do not
 // make this pub it is fine
 fn write_place<T>(place: *mut T, value: T) {
@@ -74,150 +182,849 @@ fn write_place<T>(place: *mut T, value: T) {
     }
 }
 
+/// Reads a NUL-terminated UTF-8 string passed in from C, recording the
+/// reason in the last error if it is null or malformed.
+unsafe fn read_c_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        set_last_error!(ErrorKind::InvalidArgument, "Unexpected null string");
+        return None;
+    }
+    match CStr::from_ptr(s).to_str() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            set_last_error!(ErrorKind::InvalidUtf8, "Invalid UTF-8 string: {}", e);
+            None
+        }
+    }
+}
+
+/// Reads a length-delimited buffer passed in from C.
+unsafe fn read_buffer<'a>(v: *const u8, len: u32) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if v.is_null() {
+        set_last_error!(ErrorKind::InvalidArgument, "Null buffer with length {}", len);
+        None
+    } else {
+        Some(::std::slice::from_raw_parts(v, len as usize))
+    }
+}
+
+/// Reads a length-delimited UTF-8 string passed in from C.
+unsafe fn read_str_len<'a>(v: *const u8, len: u32) -> Option<&'a str> {
+    let v = match read_buffer(v, len) {
+        Some(v) => v,
+        None => return None
+    };
+    match ::std::str::from_utf8(v) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            set_last_error!(ErrorKind::InvalidUtf8, "Invalid UTF-8 string: {}", e);
+            None
+        }
+    }
+}
+
+/// Converts a length for the C API, which passes lengths as `u32`,
+/// recording an error if it does not fit.
+pub(crate) fn len_u32(len: usize) -> Option<u32> {
+    if len > ::std::u32::MAX as usize {
+        set_last_error!(ErrorKind::Unsupported, "Length {} does not fit in 32 bits", len);
+        None
+    } else {
+        Some(len as u32)
+    }
+}
+
+/// Runs `f` on the object behind `v` if it is a `T`.
+fn with_object<T: 'static, R, F: FnOnce(&T) -> R>(v: &Value, e: &ExecutorImpl, f: F) -> Option<R> {
+    if !v.is_object() {
+        return None;
+    }
+
+    let ctx = ValueContext::new(v, e.get_object_pool());
+    let ret = match ctx.as_object_direct().as_any().downcast_ref::<T>() {
+        Some(v) => Some(f(v)),
+        None => None
+    };
+    ret
+}
+
+/// Allocates `obj` and writes a reference to it to `ret_place`. Fails if
+/// the executor is over its memory limit.
+fn allocate_into(ret_place: *mut Value, e: &mut ExecutorImpl, obj: Box<Object>) -> i32 {
+    ffi_guard(1, || write_allocated(ret_place, exec_state::allocate(e, obj)))
+}
+
+fn write_allocated(ret_place: *mut Value, id: Option<usize>) -> i32 {
+    match id {
+        Some(id) => {
+            write_place(ret_place, Value::Object(id));
+            0
+        },
+        None => 1
+    }
+}
+
+fn allocate_string_into(ret_place: *mut Value, e: &mut ExecutorImpl, v: &str) -> i32 {
+    ffi_guard(1, || write_allocated(ret_place, exec_state::allocate_sized(e, Box::new(v.to_string()), v.len())))
+}
+
+/// Returns `default` instead of unwinding across `extern "C"` if `f` panics.
+pub(crate) fn ffi_guard<T, F: FnOnce() -> T>(default: T, f: F) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => v,
+        Err(e) => {
+            set_last_error_from_panic!(e, "Unexpected panic");
+            default
+        }
+    }
+}
+
 /// Returns a reference to the requested static object, otherwise null.
 ///
 /// It should be noted that the address of the same `Value` is **not**
 /// guaranteed to be consistent and any attempts to mutate the state
 /// of the executor may result in undefined behavior.
+///
+/// Returns 1 and writes null if `key` is not a valid string.
 #[no_mangle]
 pub extern "C" fn hexagon_ort_executor_impl_get_static_object(
     ret_place: *mut Value,
     e: &ExecutorImpl,
     key: *const c_char,
-) {
-    let key = unsafe { CStr::from_ptr(key).to_str().unwrap() };
-    let obj = match e.get_static_object(key) {
+) -> i32 {
+    let key = match unsafe { read_c_str(key) } {
         Some(v) => v,
-        None => return write_place(ret_place, Value::Null)
+        None => {
+            write_place(ret_place, Value::Null);
+            return 1;
+        }
     };
-    write_place(ret_place, (*obj).into())
+    ffi_guard(1, || {
+        let obj = match e.get_static_object(key) {
+            Some(v) => v,
+            None => {
+                write_place(ret_place, Value::Null);
+                return 0;
+            }
+        };
+        write_place(ret_place, (*obj).into());
+        0
+    })
 }
 
-#[no_mangle]
-pub extern "C" fn hexagon_ort_executor_impl_invoke(
-    ret_place: *mut Value,
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InvokeStatus {
+    Ok = 0,
+    VMError = 1,
+    Panic = 2,
+    NativeError = 3,
+    InvalidArgument = 4,
+    OutOfMemory = 5,
+    CallBudgetExhausted = 6,
+    Interrupted = 7,
+    Timeout = 8
+}
+
+pub(crate) struct InvokeError {
+    pub(crate) status: InvokeStatus,
+    pub(crate) message: String,
+    pub(crate) value: Option<Value>
+}
+
+impl InvokeStatus {
+    fn from_raised(kind: ErrorKind) -> InvokeStatus {
+        match kind {
+            ErrorKind::OutOfMemory => InvokeStatus::OutOfMemory,
+            ErrorKind::CallBudgetExhausted => InvokeStatus::CallBudgetExhausted,
+            ErrorKind::Interrupted => InvokeStatus::Interrupted,
+            ErrorKind::Timeout => InvokeStatus::Timeout,
+            _ => InvokeStatus::NativeError
+        }
+    }
+}
+
+fn deadline_after(timeout_ms: u32) -> Instant {
+    Instant::now() + Duration::from_millis(timeout_ms as u64)
+}
+
+/// Runs `f` as an invocation started by the host.
+///
+/// `roots` are kept alive for the whole call, so the host may pass values
+/// it has not rooted itself. Garbage is collected before `f` runs if this
+/// is the outermost invocation and a threshold has been reached.
+fn run_invocation<F: FnOnce(&mut ExecutorImpl) -> Value>(
+    e: &mut ExecutorImpl,
+    context: &str,
+    deadline: Option<Instant>,
+    roots: &[Value],
+    f: F
+) -> Result<Value, InvokeError> {
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let _roots: SmallVec<[Handle; 4]> = roots.iter().map(|v| HandleTable::root(e, *v)).collect();
+        let guard = exec_state::begin_invocation(e, deadline);
+        if guard.is_outermost() {
+            exec_state::collect_if_needed(e);
+        }
+        exec_state::safepoint(e);
+        f(e)
+    }));
+    match result {
+        Ok(v) => Ok(v),
+        Err(payload) => {
+            let (kind, message) = last_error::describe_panic(payload);
+            let (kind, status, thrown) = match last_error::take_raised_value(&message) {
+                Some((raised, thrown)) => (raised, InvokeStatus::from_raised(raised), thrown),
+                None => if kind == ErrorKind::VMError {
+                    (kind, InvokeStatus::VMError, None)
+                } else {
+                    (kind, InvokeStatus::Panic, None)
+                }
+            };
+            set_last_error!(kind, "{}: {}", context, message);
+            let value = thrown.as_ref().map(|h| h.get());
+            exec_state::with(e, |s| s.thrown = thrown);
+            Err(InvokeError {
+                status: status,
+                message: message,
+                value: value
+            })
+        }
+    }
+}
+
+pub(crate) fn invoke_value(
+    e: &mut ExecutorImpl,
+    target: Value,
+    this: Value,
+    args: &[Value],
+    deadline: Option<Instant>
+) -> Result<Value, InvokeError> {
+    let mut roots: SmallVec<[Value; 4]> = SmallVec::new();
+    roots.push(target);
+    roots.push(this);
+    roots.extend(args.iter().cloned());
+
+    run_invocation(e, "Invoke failed", deadline, &roots, |e| {
+        e.invoke(target, this, None, args);
+        e.get_current_frame().pop_exec()
+    })
+}
+
+pub(crate) fn run_callable_value(
     e: &mut ExecutorImpl,
+    key: &str,
+    deadline: Option<Instant>
+) -> Result<(), InvokeError> {
+    run_invocation(e, "Callable failed", deadline, &[], |e| {
+        e.run_callable(key);
+        Value::Null
+    }).map(|_| ())
+}
+
+fn write_invoke_error(err_place: *mut *mut c_char, err: InvokeError) -> u32 {
+    if !err_place.is_null() {
+        let msg = CString::new(err.message.replace('\0', "")).unwrap();
+        write_place(err_place, msg.into_raw());
+    }
+    err.status as u32
+}
+
+unsafe fn read_invoke_args<'a>(
     target: *const Value,
     this: *const Value,
     args: *const Value,
     n_args: u32
-) {
+) -> Option<(Value, Value, &'a [Value])> {
+    if args.is_null() && n_args != 0 {
+        set_last_error!(ErrorKind::InvalidArgument, "Null argument list with {} arguments", n_args);
+        return None;
+    }
+
     let target = if target.is_null() {
         Value::Null
     } else {
-        unsafe { *target }
+        *target
     };
     let this = if this.is_null() {
         Value::Null
     } else {
-        unsafe { *this }
+        *this
     };
-    let args: &[Value] = unsafe {
+    let args: &[Value] = if n_args == 0 {
+        &[]
+    } else {
         ::std::slice::from_raw_parts(args, n_args as usize)
     };
+    Some((target, this, args))
+}
 
-    let result = catch_unwind(AssertUnwindSafe(
-        || e.invoke(target, this, None, args)
-    ));
-    write_place(ret_place, match result {
-        Ok(_) => e.get_current_frame().pop_exec(),
-        Err(e) => {
-            if let Ok(e) = e.downcast::<VMError>() {
-                eprintln!("Invoke failed: {}", e.unwrap().to_string());
-            } else {
-                eprintln!("Unknown error");
-            }
-            Value::Null
-        }
+/// Writes `Value::Null` to `ret_place` on failure.
+///
+/// Use `hexagon_ort_executor_impl_invoke_checked` to tell a failure
+/// apart from a function that returns null.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_invoke(
+    ret_place: *mut Value,
+    e: &mut ExecutorImpl,
+    target: *const Value,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32
+) {
+    let (target, this, args) = match unsafe { read_invoke_args(target, this, args, n_args) } {
+        Some(v) => v,
+        None => return write_place(ret_place, Value::Null)
+    };
+    write_place(ret_place, match invoke_value(e, target, this, args, None) {
+        Ok(v) => v,
+        Err(_) => Value::Null
     })
 }
 
+/// Returns an `InvokeStatus`.
+///
+/// On success the return value is written to `ret_place`. On failure
+/// `ret_place` is left untouched and, if `err_place` is not null, an error
+/// message is written to it, which should be released with
+/// `hexagon_glue_destroy_cstring`.
 #[no_mangle]
-pub unsafe extern "C" fn hexagon_ort_executor_impl_set_stack_limit(
+pub extern "C" fn hexagon_ort_executor_impl_invoke_checked(
+    ret_place: *mut Value,
+    err_place: *mut *mut c_char,
     e: &mut ExecutorImpl,
-    limit: u32
-) {
-    e.set_stack_limit(limit as usize);
+    target: *const Value,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32
+) -> u32 {
+    invoke_checked(ret_place, err_place, e, target, this, args, n_args, None)
+}
+
+/// Same as `hexagon_ort_executor_impl_invoke_checked`, but stops with
+/// `Timeout` at the first safe point after `timeout_ms` milliseconds.
+/// Inside another invocation, the earlier of the two deadlines applies.
+///
+/// This is not a wall-clock limit. Safe points are host calls, so a
+/// script that loops without calling the host runs past its deadline.
+///
+/// Native functions can query the time left with
+/// `hexagon_ort_executor_impl_get_remaining_time_ms`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_invoke_with_timeout(
+    ret_place: *mut Value,
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    target: *const Value,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32,
+    timeout_ms: u32
+) -> u32 {
+    invoke_checked(ret_place, err_place, e, target, this, args, n_args, Some(deadline_after(timeout_ms)))
 }
 
+/// Same as `hexagon_ort_executor_impl_invoke_checked`, but on failure
+/// writes the error as a value to `err_place` instead of a C string.
+///
+/// The error value is the one thrown with
+/// `hexagon_ort_executor_impl_throw_value` if the invocation failed that
+/// way, otherwise a string holding the error message. It is not rooted.
+/// Null is written if the message string cannot be allocated.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_executor_impl_get_argument(
+pub extern "C" fn hexagon_ort_executor_impl_invoke_catch(
     ret_place: *mut Value,
-    e: &ExecutorImpl,
-    id: u32
-) -> i32 {
-    match e.get_current_frame().get_argument(id as usize) {
-        Some(v) => {
+    err_place: *mut Value,
+    e: &mut ExecutorImpl,
+    target: *const Value,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32
+) -> u32 {
+    let (target, this, args) = match unsafe { read_invoke_args(target, this, args, n_args) } {
+        Some(v) => v,
+        None => return InvokeStatus::InvalidArgument as u32
+    };
+    match invoke_value(e, target, this, args, None) {
+        Ok(v) => {
             write_place(ret_place, v);
-            0
+            InvokeStatus::Ok as u32
         },
-        None => 1
+        Err(err) => {
+            if !err_place.is_null() {
+                let len = err.message.len();
+                let value = match err.value {
+                    Some(v) => v,
+                    None => match exec_state::allocate_sized(e, Box::new(err.message), len) {
+                        Some(id) => Value::Object(id),
+                        None => Value::Null
+                    }
+                };
+                write_place(err_place, value);
+            }
+            err.status as u32
+        }
     }
 }
 
-#[no_mangle]
-pub extern "C" fn hexagon_ort_executor_impl_get_n_arguments(
-    e: &ExecutorImpl
+fn invoke_checked(
+    ret_place: *mut Value,
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    target: *const Value,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32,
+    deadline: Option<Instant>
 ) -> u32 {
-    e.get_current_frame().get_n_arguments() as u32
+    let (target, this, args) = match unsafe { read_invoke_args(target, this, args, n_args) } {
+        Some(v) => v,
+        None => return InvokeStatus::InvalidArgument as u32
+    };
+    match invoke_value(e, target, this, args, deadline) {
+        Ok(v) => {
+            write_place(ret_place, v);
+            InvokeStatus::Ok as u32
+        },
+        Err(err) => write_invoke_error(err_place, err)
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn hexagon_ort_function_destroy(
-    f: *mut Function
+pub unsafe extern "C" fn hexagon_ort_executor_impl_set_stack_limit(
+    e: &mut ExecutorImpl,
+    limit: u32
 ) {
-    Box::from_raw(f);
-}
-
-struct NativeFunctionGuard {
-    destructor: Option<extern "C" fn (*const ())>,
-    user_data: *const (),
-    always_false: bool
+    ffi_guard((), || e.set_stack_limit(limit as usize))
 }
 
-impl Drop for NativeFunctionGuard {
-    fn drop(&mut self) {
-        if let Some(dtor) = self.destructor {
-            (dtor)(self.user_data);
-        }
-    }
+/// Forces a full garbage collection.
+///
+/// Values held by the host are freed unless they are rooted with
+/// `hexagon_ort_handle_create`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_gc(
+    e: &mut ExecutorImpl
+) -> i32 {
+    ffi_guard(1, || {
+        e.gc(true);
+        0
+    })
 }
 
+/// `approx_bytes` is estimated from the live object count plus the
+/// contents of live byte buffers. See `hexagon_ort_executor_impl_set_memory_limit`.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_function_load_native(
-    cb: extern "C" fn (*mut Value /* ret_place */, &mut ExecutorImpl, *const ()) -> i32,
-    destructor: Option<extern "C" fn (*const ())>,
-    user_data: *const ()
-) -> *mut Function {
-    let guard = NativeFunctionGuard {
-        destructor: destructor,
-        user_data: user_data,
-        always_false: false
-    };
-
-    let f = Box::new(move |e: &mut ExecutorImpl| {
-        let _v = guard.always_false;
-
-        unsafe {
-            let mut ret: Value = ::std::mem::zeroed();
-            let err = cb(&mut ret, e, user_data);
-
-            if err != 0 {
-                panic!(VMError::from("Native function returns error"));
-            }
-
-            ret
-        }
-    });
-    let f = Function::from_native(f);
-    Box::into_raw(Box::new(f))
+pub extern "C" fn hexagon_ort_executor_impl_get_heap_stats(
+    ret_place: *mut HeapStats,
+    e: &ExecutorImpl
+) -> i32 {
+    ffi_guard(1, || {
+        write_place(ret_place, exec_state::heap_stats(e));
+        0
+    })
 }
 
+/// Collects garbage when an invocation starts once the number of live
+/// objects reaches `threshold`. Only invocations started from outside any
+/// other invocation collect, and the target, `this` and arguments passed
+/// to them are kept alive.
+///
+/// Any other value the host holds across such a call must be rooted with
+/// `hexagon_ort_handle_create`. A threshold of 0 disables this.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_function_enable_optimization(
-    f: &mut Function
+pub extern "C" fn hexagon_ort_executor_impl_set_gc_threshold(
+    e: &mut ExecutorImpl,
+    threshold: u64
 ) {
-    f.enable_optimization();
+    ffi_guard((), || exec_state::with_mut(e, |s| s.gc_threshold = threshold as usize))
+}
+
+/// Limits the approximate heap size (see `hexagon_ort_executor_impl_get_heap_stats`)
+/// to `limit` bytes. 0 removes the limit.
+///
+/// The limit is approximate and only enforced at host calls:
+///
+/// - Each allocation made through this API is checked before it happens,
+///   including the contents of strings and byte buffers.
+/// - Byte buffers keep counting their contents while they live. Strings
+///   are core objects, so once created they count as a fixed-size object
+///   whatever their length.
+/// - Objects that scripts allocate inside hexagon-vm-core are counted by
+///   number only and cannot be refused one by one. A script over the limit
+///   is stopped at its next safe point, that is its next call into the
+///   host, with a VM error that the invoke functions report as
+///   `OutOfMemory`. A script that allocates without calling the host is
+///   not stopped.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_set_memory_limit(
+    e: &mut ExecutorImpl,
+    limit: u64
+) {
+    ffi_guard((), || exec_state::with_mut(e, |s| s.memory_limit = limit as usize))
+}
+
+/// Limits the number of calls each invocation started by the host may
+/// make. 0 removes the limit.
+///
+/// One call is charged at the start of an invocation and at every native
+/// function or proxy call, and native code may charge more with
+/// `hexagon_ort_executor_impl_charge_calls`. Once the budget is used up,
+/// the invocation stops with `CallBudgetExhausted`.
+///
+/// Only calls are counted. hexagon-vm-core does not expose its instruction
+/// count, so a script looping without calling the host is not stopped.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_set_call_budget(
+    e: &mut ExecutorImpl,
+    calls: u64
+) {
+    ffi_guard((), || exec_state::with_mut(e, |s| s.call_budget = calls))
+}
+
+/// Returns the calls charged to the current or last invocation.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_get_calls_used(
+    e: &ExecutorImpl
+) -> u64 {
+    exec_state::with(e, |s| s.calls_used)
+}
+
+/// Charges `n` calls to the budget. Returns 1 if it is used up, in which
+/// case the native function should return an error: the invocation then
+/// fails with `CallBudgetExhausted`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_charge_calls(
+    e: &ExecutorImpl,
+    n: u64
+) -> i32 {
+    if exec_state::charge_calls(e, n) {
+        0
+    } else {
+        1
+    }
+}
+
+/// Returns a handle that can be sent to another thread to interrupt
+/// invocations on this executor. Release it with
+/// `hexagon_ort_interrupt_handle_destroy`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_create_interrupt_handle(
+    e: &mut ExecutorImpl
+) -> *mut InterruptHandle {
+    ffi_guard(null_mut(), || Box::into_raw(Box::new(exec_state::with_mut(e, |s| s.interrupt.clone()))))
+}
+
+/// Makes the running invocation stop with `Interrupted` at its next safe
+/// point. A trigger sent while nothing runs is discarded when the next
+/// invocation starts. Safe to call from any thread.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_interrupt_handle_trigger(
+    h: &InterruptHandle
+) {
+    h.trigger();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn hexagon_ort_interrupt_handle_destroy(
+    h: *mut InterruptHandle
+) {
+    Box::from_raw(h);
+}
+
+/// Returns the milliseconds left before the running invocation times out,
+/// or -1 if it has no timeout.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_get_remaining_time_ms(
+    e: &ExecutorImpl
+) -> i64 {
+    match exec_state::with(e, |s| s.deadline) {
+        Some(deadline) => {
+            let now = Instant::now();
+            if deadline > now {
+                let left = deadline - now;
+                (left.as_secs() * 1000 + (left.subsec_nanos() / 1000000) as u64) as i64
+            } else {
+                0
+            }
+        },
+        None => -1
+    }
+}
+
+/// Sets the error a native function or proxy hook fails with.
+///
+/// Takes effect when the callback returns non-zero: the VM error raised
+/// for it carries `message` instead of the generic one, and the invoking
+/// host gets the same message through the last error and `err_place`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_throw(
+    _e: &ExecutorImpl,
+    message: *const c_char
+) -> i32 {
+    let message = match unsafe { read_c_str(message) } {
+        Some(v) => v,
+        None => return 1
+    };
+    last_error::set_thrown(message.to_string());
+    0
+}
+
+/// Same as `hexagon_ort_executor_impl_throw`, using the string form of `v`
+/// as the message, or its type name if it has none.
+///
+/// `v` is kept alive and becomes the error value of the invocation, read
+/// back with `hexagon_ort_executor_impl_invoke_catch` or
+/// `hexagon_ort_executor_impl_get_thrown_value`. If the callback returns
+/// zero instead, `v` is released. Scripts still see a `VMError` with the
+/// message: hexagon-vm-core has no way to catch a value.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_throw_value(
+    e: &mut ExecutorImpl,
+    v: &Value
+) -> i32 {
+    ffi_guard(1, || {
+        let message = {
+            let ctx = ValueContext::new(v, e.get_object_pool());
+            match catch_unwind(AssertUnwindSafe(|| ctx.to_str().to_string())) {
+                Ok(v) => v,
+                Err(_) => match *v {
+                    Value::Object(_) => ctx.as_object_direct().typename().to_string(),
+                    _ => "value".to_string()
+                }
+            }
+        };
+        let handle = HandleTable::root(e, *v);
+        last_error::set_thrown_value(message, Some(handle));
+        0
+    })
+}
+
+/// Writes the value thrown by the last failed invocation. Returns 1 if
+/// it was not thrown with `hexagon_ort_executor_impl_throw_value`.
+///
+/// The value stays alive until the next invocation started by the host.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_get_thrown_value(
+    ret_place: *mut Value,
+    e: &ExecutorImpl
+) -> i32 {
+    match exec_state::with(e, |s| s.thrown.as_ref().map(|h| h.get())) {
+        Some(v) => {
+            write_place(ret_place, v);
+            0
+        },
+        None => 1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_get_argument(
+    ret_place: *mut Value,
+    e: &ExecutorImpl,
+    id: u32
+) -> i32 {
+    ffi_guard(1, || match e.get_current_frame().get_argument(id as usize) {
+        Some(v) => {
+            write_place(ret_place, v);
+            0
+        },
+        None => {
+            set_last_error!(ErrorKind::InvalidArgument, "Argument index out of bound: {}", id);
+            1
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_impl_get_n_arguments(
+    e: &ExecutorImpl
+) -> u32 {
+    ffi_guard(0, || e.get_current_frame().get_n_arguments() as u32)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn hexagon_ort_function_destroy(
+    f: *mut Function
+) {
+    ffi_guard((), || {
+        Box::from_raw(f);
+    })
+}
+
+struct NativeFunctionGuard {
+    destructor: Option<extern "C" fn (*const ())>,
+    user_data: *const (),
+    always_false: bool
+}
+
+impl Drop for NativeFunctionGuard {
+    fn drop(&mut self) {
+        if let Some(dtor) = self.destructor {
+            (dtor)(self.user_data);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_load_native(
+    cb: extern "C" fn (*mut Value /* ret_place */, &mut ExecutorImpl, *const ()) -> i32,
+    destructor: Option<extern "C" fn (*const ())>,
+    user_data: *const ()
+) -> *mut Function {
+    let guard = NativeFunctionGuard {
+        destructor: destructor,
+        user_data: user_data,
+        always_false: false
+    };
+
+    let f = Box::new(move |e: &mut ExecutorImpl| {
+        let _v = guard.always_false;
+
+        exec_state::safepoint(e);
+
+        unsafe {
+            let mut ret: Value = ::std::mem::zeroed();
+            let err = cb(&mut ret, e, user_data);
+
+            if err != 0 {
+                raise_native_error!("Native function returns error");
+            }
+            last_error::take_thrown();
+
+            ret
+        }
+    });
+    let f = Function::from_native(f);
+    Box::into_raw(Box::new(f))
+}
+
+/// Callback type for `hexagon_ort_function_load_native_ex`.
+///
+/// `this` and `args` point to values owned by the current frame and are
+/// only valid during the call. `args` is null when `n_args` is zero.
+///
+/// On failure, the callback returns non-zero and may store a message in
+/// `*err_place`, allocated with `hexagon_glue_alloc`; the bridge releases it.
+pub type NativeFunctionEx = extern "C" fn (
+    ret_place: *mut Value,
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32,
+    user_data: *const ()
+) -> i32;
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_load_native_ex(
+    cb: NativeFunctionEx,
+    destructor: Option<extern "C" fn (*const ())>,
+    user_data: *const ()
+) -> *mut Function {
+    load_native_ex(cb, None, destructor, user_data)
+}
+
+/// Same as `hexagon_ort_function_load_native_ex`, but arguments are
+/// checked against `signature` before `cb` runs. See `Signature` for the
+/// format.
+///
+/// Returns null and records an `InvalidArgument` error if the signature
+/// is malformed. `destructor` is not called in that case.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_load_native_typed(
+    cb: NativeFunctionEx,
+    signature: *const c_char,
+    destructor: Option<extern "C" fn (*const ())>,
+    user_data: *const ()
+) -> *mut Function {
+    let signature = match unsafe { read_c_str(signature) } {
+        Some(v) => v,
+        None => return null_mut()
+    };
+    let signature = match Signature::parse(signature) {
+        Ok(v) => v,
+        Err(e) => {
+            set_last_error!(ErrorKind::InvalidArgument, "Invalid signature: {}", e);
+            return null_mut();
+        }
+    };
+    load_native_ex(cb, Some(signature), destructor, user_data)
+}
+
+fn load_native_ex(
+    cb: NativeFunctionEx,
+    signature: Option<Signature>,
+    destructor: Option<extern "C" fn (*const ())>,
+    user_data: *const ()
+) -> *mut Function {
+    let guard = NativeFunctionGuard {
+        destructor: destructor,
+        user_data: user_data,
+        always_false: false
+    };
+
+    let f = Box::new(move |e: &mut ExecutorImpl| {
+        let _v = guard.always_false;
+
+        let (this, args) = exec_state::enter_native(e);
+
+        if let Some(ref signature) = signature {
+            if let Err(msg) = signature.check(e, &args) {
+                panic!(VMError::from(msg.as_str()));
+            }
+        }
+
+        call_native_ex(e, cb, this, &args, user_data)
+    });
+    let f = Function::from_native(f);
+    Box::into_raw(Box::new(f))
+}
+
+/// Calls a `NativeFunctionEx` and raises its error, if any, in the VM.
+pub(crate) fn call_native_ex(
+    e: &mut ExecutorImpl,
+    cb: NativeFunctionEx,
+    this: Value,
+    args: &[Value],
+    user_data: *const ()
+) -> Value {
+    let mut ret = Value::Null;
+    let mut err_msg: *mut c_char = null_mut();
+    let err = cb(
+        &mut ret,
+        &mut err_msg,
+        e,
+        &this,
+        if args.len() > 0 { &args[0] } else { null() },
+        args.len() as u32,
+        user_data
+    );
+
+    if err != 0 {
+        let msg = if err_msg.is_null() {
+            "Native function returns error".to_string()
+        } else {
+            let msg = unsafe { CStr::from_ptr(err_msg) }.to_string_lossy().into_owned();
+            unsafe { hexagon_glue_free(err_msg as *mut u8); }
+            msg
+        };
+        raise_native_error!(msg.as_str());
+    } else if !err_msg.is_null() {
+        unsafe { hexagon_glue_free(err_msg as *mut u8); }
+    }
+    last_error::take_thrown();
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_enable_optimization(
+    f: &mut Function
+) {
+    ffi_guard((), || f.enable_optimization())
 }
 
 #[no_mangle]
@@ -226,326 +1033,1166 @@ pub extern "C" fn hexagon_ort_function_load_virtual(
     code: *const u8,
     len: u32
 ) -> *mut Function {
-    let encoding = unsafe { CStr::from_ptr(encoding).to_str().unwrap() };
-    let code = unsafe { ::std::slice::from_raw_parts(code ,len as usize) };
+    let encoding = match unsafe { read_c_str(encoding) } {
+        Some(v) => v,
+        None => return null_mut()
+    };
+    if code.is_null() && len != 0 {
+        set_last_error!(ErrorKind::InvalidArgument, "Null code buffer with length {}", len);
+        return null_mut();
+    }
+    let code: &[u8] = if len == 0 {
+        &[]
+    } else {
+        unsafe { ::std::slice::from_raw_parts(code, len as usize) }
+    };
 
-    match encoding {
+    match load_virtual(encoding, code) {
+        Some(f) => Box::into_raw(Box::new(f)),
+        None => null_mut()
+    }
+}
+
+/// Decodes and verifies a virtual function, recording the reason in the
+/// last error on failure.
+pub(crate) fn load_virtual(encoding: &str, code: &[u8]) -> Option<Function> {
+    let vinfo: VirtualFunctionInfo = match encoding {
         "json" => {
             let code = match ::std::str::from_utf8(code) {
                 Ok(v) => v,
                 Err(e) => {
-                    eprintln!("UTF-8 decoding failed: {}", e);
-                    return null_mut();
-                }
-            };
-            let vinfo: VirtualFunctionInfo = match serde_json::from_str(code) {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("JSON decoding failed: {}", e);
-                    return null_mut();
+                    set_last_error!(ErrorKind::InvalidUtf8, "UTF-8 decoding failed: {}", e);
+                    return None;
                 }
             };
-            let f = match catch_unwind(|| Function::from_virtual_info(vinfo)) {
+            match serde_json::from_str(code) {
                 Ok(v) => v,
                 Err(e) => {
-                    eprintln!("CFG verification failed: {}", match e.downcast::<VMError>() {
-                        Ok(v) => v.unwrap().to_string(),
-                        Err(_) => "Unknown error".to_string()
-                    });
-                    return null_mut();
+                    set_last_error!(ErrorKind::Decode, "JSON decoding failed: {}", e);
+                    return None;
                 }
-            };
-            Box::into_raw(Box::new(f))
+            }
         },
-        "msgpack" | "messagepack" => {
-            let vinfo: VirtualFunctionInfo = match rmp_serde::decode::from_slice(code) {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("MessagePack decoding failed: {}", e);
-                    return null_mut();
-                }
-            };
-            let f = match catch_unwind(|| Function::from_virtual_info(vinfo)) {
-                Ok(v) => v,
-                Err(e) => {
-                    eprintln!("CFG verification failed: {}", match e.downcast::<VMError>() {
-                        Ok(v) => v.unwrap().to_string(),
-                        Err(_) => "Unknown error".to_string()
-                    });
-                    return null_mut();
-                }
-            };
-            Box::into_raw(Box::new(f))
+        "msgpack" | "messagepack" => match rmp_serde::decode::from_slice(code) {
+            Ok(v) => v,
+            Err(e) => {
+                set_last_error!(ErrorKind::Decode, "MessagePack decoding failed: {}", e);
+                return None;
+            }
         },
         _ => {
-            eprintln!("Unsupported encoding: {}", encoding);
-            null_mut()
+            set_last_error!(ErrorKind::Unsupported, "Unsupported encoding: {}", encoding);
+            return None;
+        }
+    };
+
+    match catch_unwind(|| Function::from_virtual_info(vinfo)) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            let (_, msg) = last_error::describe_panic(e);
+            set_last_error!(ErrorKind::Verification, "CFG verification failed: {}", msg);
+            None
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_dump_json(
+    f: &Function
+) -> *mut c_char {
+    ffi_guard(null_mut(), || if let Some(v) = f.to_virtual_info() {
+        match serde_json::to_string(&v) {
+            Ok(v) => CString::new(v).unwrap().into_raw(),
+            Err(e) => {
+                set_last_error!(ErrorKind::Encode, "JSON encoding failed: {}", e);
+                null_mut()
+            }
         }
+    } else {
+        set_last_error!(ErrorKind::Unsupported, "Function is not virtual");
+        null_mut()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_debug_print(
+    f: &Function
+) {
+    ffi_guard((), || if let Some(v) = f.to_virtual_info() {
+        eprintln!("{:?}", v);
+    } else {
+        eprintln!("(not printable)");
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_function_bind_this(
+    f: &Function,
+    v: &Value
+) -> i32 {
+    if let Err(e) = catch_unwind(AssertUnwindSafe(|| f.bind_this(*v))) {
+        set_last_error_from_panic!(e, "Unable to bind this");
+        1
+    } else {
+        0
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_null(ret_place: *mut Value) {
+    write_place(ret_place, Value::Null)
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_bool(ret_place: *mut Value, v: u32) {
+    write_place(ret_place, Value::Bool(if v == 0 { false } else { true }))
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_i64(ret_place: *mut Value, v: i64) {
+    write_place(ret_place, Value::Int(v))
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_f64(ret_place: *mut Value, v: f64) {
+    write_place(ret_place, Value::Float(v))
+}
+
+/// Returns 1 if `v` is not a valid UTF-8 string or the executor is over
+/// its memory limit, otherwise 0.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_string(ret_place: *mut Value, v: *const c_char, e: &mut ExecutorImpl) -> i32 {
+    let v = match unsafe { read_c_str(v) } {
+        Some(v) => v,
+        None => return 1
+    };
+    allocate_string_into(ret_place, e, v)
+}
+
+/// Creates a string from `len` bytes at `v`, which may contain NULs
+/// but must be valid UTF-8.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_str_len(
+    ret_place: *mut Value,
+    v: *const u8,
+    len: u32,
+    e: &mut ExecutorImpl
+) -> i32 {
+    let v = match unsafe { read_str_len(v, len) } {
+        Some(v) => v,
+        None => return 1
+    };
+    allocate_string_into(ret_place, e, v)
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_create_from_bytes(
+    ret_place: *mut Value,
+    v: *const u8,
+    len: u32,
+    e: &mut ExecutorImpl
+) -> i32 {
+    let v = match unsafe { read_buffer(v, len) } {
+        Some(v) => v,
+        None => return 1
+    };
+    ffi_guard(1, || write_allocated(ret_place, exec_state::allocate_bytes(e, v.to_vec())))
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_i64(ret_place: *mut i64, v: &Value) -> i32 {
+    match *v {
+        Value::Int(v) => {
+            write_place(ret_place, v);
+            0
+        },
+        _ => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not an integer");
+            1
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_f64(ret_place: *mut f64, v: &Value) -> i32 {
+    match *v {
+        Value::Float(v) => {
+            write_place(ret_place, v);
+            0
+        },
+        _ => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not a float");
+            1
+        }
+    }
+}
+
+/// Returns 0 if `v` is null, otherwise 1.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_null(v: &Value) -> i32 {
+    match *v {
+        Value::Null => 0,
+        _ => 1
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_bool(ret_place: *mut i32, v: &Value) -> i32 {
+    match *v {
+        Value::Bool(v) => {
+            write_place(ret_place, if v { 1 } else { 0 });
+            0
+        },
+        _ => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not a boolean");
+            1
+        }
+    }
+}
+
+/// Returns a tag for the type of `v`: `N`, `B`, `I`, `F`, or `O` for any
+/// object. Use `hexagon_ort_value_get_type_ex` to tell objects apart.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_get_type(v: &Value) -> u8 {
+    match *v {
+        Value::Bool(_) => b'B',
+        Value::Float(_) => b'F',
+        Value::Int(_) => b'I',
+        Value::Null => b'N',
+        Value::Object(_) => b'O'
+    }
+}
+
+/// Returns a tag for the type of `v`:
+///
+/// - `N`: null
+/// - `B`: boolean
+/// - `I`: integer
+/// - `F`: float
+/// - `S`: string
+/// - `Y`: byte buffer
+/// - `A`: array
+/// - `M`: map
+/// - `P`: object proxy
+/// - `C`: function
+/// - `O`: any other object
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_get_type_ex(v: &Value, executor: &ExecutorImpl) -> u8 {
+    match *v {
+        Value::Object(_) => ffi_guard(b'O', || {
+            let ctx = ValueContext::new(v, executor.get_object_pool());
+            let obj = ctx.as_object_direct().as_any();
+            if obj.is::<String>() {
+                b'S'
+            } else if obj.is::<Bytes>() {
+                b'Y'
+            } else if obj.is::<Array>() {
+                b'A'
+            } else if obj.is::<Map>() {
+                b'M'
+            } else if obj.is::<ObjectProxy>() {
+                b'P'
+            } else if obj.is::<Function>() {
+                b'C'
+            } else {
+                b'O'
+            }
+        }),
+        _ => hexagon_ort_value_get_type(v)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_string(v: &Value, executor: &ExecutorImpl) -> *mut c_char {
+    match catch_unwind(AssertUnwindSafe(|| CString::new(ValueContext::new(
+        v,
+        &executor.get_object_pool()
+    ).to_str().as_ref()).unwrap().into_raw())) {
+        Ok(v) => v,
+        Err(e) => {
+            set_last_error_from_panic!(e, "Unable to read string");
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_is_string(v: &Value, executor: &ExecutorImpl) -> u32 {
+    ffi_guard(0, || match with_object(v, executor, |_: &String| ()) {
+        Some(_) => 1,
+        None => 0
+    })
+}
+
+/// Returns a pointer to the UTF-8 contents of a string value and writes
+/// its length to `len_place`, or null if `v` is not a string or is
+/// longer than `u32::MAX` bytes.
+///
+/// Nothing is copied. The pointer stays valid as long as the string object
+/// is alive.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_str(
+    len_place: *mut u32,
+    v: &Value,
+    executor: &ExecutorImpl
+) -> *const u8 {
+    ffi_guard(null(), || match with_object(v, executor, |s: &String| (s.as_ptr(), s.len())) {
+        Some((ptr, len)) => match len_u32(len) {
+            Some(len) => {
+                write_place(len_place, len);
+                ptr
+            },
+            None => null()
+        },
+        None => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not a string");
+            null()
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_is_bytes(v: &Value, executor: &ExecutorImpl) -> u32 {
+    ffi_guard(0, || match with_object(v, executor, |_: &Bytes| ()) {
+        Some(_) => 1,
+        None => 0
+    })
+}
+
+/// Same as `hexagon_ort_value_read_str`, but for byte buffers.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_read_bytes(
+    len_place: *mut u32,
+    v: &Value,
+    executor: &ExecutorImpl
+) -> *const u8 {
+    ffi_guard(null(), || match with_object(v, executor, |b: &Bytes| (b.data.as_ptr(), b.data.len())) {
+        Some((ptr, len)) => match len_u32(len) {
+            Some(len) => {
+                write_place(len_place, len);
+                ptr
+            },
+            None => null()
+        },
+        None => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not a byte buffer");
+            null()
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_value_to_object_handle<'a>(v: &Value, executor: &'a ExecutorImpl) -> *mut ObjectHandle<'a> {
+    if let Value::Object(id) = *v {
+        ffi_guard(null_mut(), || Box::into_raw(Box::new(executor.get_object_pool().get(id))))
+    } else {
+        set_last_error!(ErrorKind::TypeMismatch, "Value is not an object");
+        null_mut()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_handle_to_object_proxy(handle: &ObjectHandle) -> *const ObjectProxy {
+    match handle.as_any().downcast_ref::<ObjectProxy>() {
+        Some(v) => v,
+        None => {
+            set_last_error!(ErrorKind::TypeMismatch, "Object is not an ObjectProxy");
+            null()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_handle_to_function(handle: &ObjectHandle) -> *const Function {
+    match handle.as_any().downcast_ref::<Function>() {
+        Some(v) => v,
+        None => {
+            set_last_error!(ErrorKind::TypeMismatch, "Object is not a Function");
+            null()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn hexagon_ort_object_handle_destroy(h: *mut ObjectHandle) {
+    ffi_guard((), || {
+        Box::from_raw(h);
+    })
+}
+
+/// Moves `p` into the object pool and writes a reference to it to
+/// `ret_place`. Returns 0 on success. `p` is consumed either way.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_pin_object_proxy(
+    ret_place: *mut Value,
+    e: &mut ExecutorImpl,
+    p: *mut ObjectProxy
+) -> i32 {
+    let mut p = unsafe {
+        Box::from_raw(p)
+    };
+    match catch_unwind(AssertUnwindSafe(|| methods::install(e, &mut p))) {
+        Ok(true) => {},
+        Ok(false) => return 1,
+        Err(e) => {
+            set_last_error_from_panic!(e, "Unable to install proxy methods");
+            return 1;
+        }
+    }
+    allocate_into(ret_place, e, p)
+}
+
+/// Same as `hexagon_ort_executor_pin_object_proxy`, but for functions.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_executor_pin_function(
+    ret_place: *mut Value,
+    e: &mut ExecutorImpl,
+    f: *mut Function
+) -> i32 {
+    let f = unsafe {
+        Box::from_raw(f)
+    };
+    allocate_into(ret_place, e, f)
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_create(data: *const ()) -> *mut ObjectProxy {
+    Box::into_raw(Box::new(ObjectProxy::new(data)))
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_get_data(
+    p: &ObjectProxy
+) -> *const () {
+    p.data
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn hexagon_ort_object_proxy_destroy(
+    p: *mut ObjectProxy
+) {
+    ffi_guard((), || {
+        Box::from_raw(p);
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_freeze(
+    p: &mut ObjectProxy
+) {
+    p.frozen = true;
+}
+
+/// Returns 1 if `key` is not a valid string, otherwise 0.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_add_const_field(
+    p: &mut ObjectProxy,
+    name: *const c_char
+) -> i32 {
+    let name = match unsafe { read_c_str(name) } {
+        Some(v) => v,
+        None => return 1
+    };
+    p.const_fields.insert(name.to_string());
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_destructor(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::Destructor>
+) {
+    p.destructor = f;
+}
+
+/// Sets the static field `k` to `v`, or removes it if `v` is null.
+/// Returns 1 if `k` is not a valid string, otherwise 0.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_static_field(
+    p: &mut ObjectProxy,
+    k: *const c_char,
+    v: *const Value
+) -> i32 {
+    let k = match unsafe { read_c_str(k) } {
+        Some(v) => v,
+        None => return 1
+    };
+    if v.is_null() {
+        p.static_fields.borrow_mut().remove(k);
+    } else {
+        p.static_fields.borrow_mut().insert(k.to_string(), unsafe { *v });
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_call(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnCall>
+) {
+    p.on_call = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_get_field(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnGetField>
+) {
+    p.on_get_field = f;
+}
+
+/// Called for assignments to fields that are neither const nor static.
+/// Existing static fields are updated in place without calling `f`, so
+/// reads and writes agree.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_set_field(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnSetField>
+) {
+    p.on_set_field = f;
+}
+
+/// See `OnTypename` for who owns the returned string.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_typename(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnTypename>
+) {
+    p.on_typename = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_to_i64(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnToI64>
+) {
+    p.on_to_i64 = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_to_f64(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnToF64>
+) {
+    p.on_to_f64 = f;
+}
+
+/// See `OnToStr` for who owns the returned string.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_to_str(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnToStr>
+) {
+    p.on_to_str = f;
+}
+
+/// See `OnToString` for who owns the returned string.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_to_string(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnToString>
+) {
+    p.on_to_string = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_to_bool(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnToBool>
+) {
+    p.on_to_bool = f;
+}
+
+/// Used for comparisons and equality tests with the proxy on the left.
+/// Without it, ordering a proxy raises an error and it is only equal to
+/// itself.
+///
+/// hexagon-vm-core asks the left operand only, so a comparison with the
+/// proxy on the right does not reach `f`. Arithmetic operators are not
+/// dispatched to objects at all and have no hook.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_compare(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnCompare>
+) {
+    p.on_compare = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_has_field(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnHasField>
+) {
+    p.on_has_field = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_delete_field(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnDeleteField>
+) {
+    p.on_delete_field = f;
+}
+
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_set_on_enumerate_fields(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnEnumerateFields>
+) {
+    p.on_enumerate_fields = f;
+}
+
+/// Runs `f` on the pinned proxy behind `v`, turning panics raised by its
+/// hooks into errors.
+fn with_proxy<R, F: FnOnce(&ObjectProxy) -> R>(v: &Value, e: &ExecutorImpl, f: F) -> Result<R, i32> {
+    match catch_unwind(AssertUnwindSafe(|| with_object(v, e, f))) {
+        Ok(Some(v)) => Ok(v),
+        Ok(None) => {
+            set_last_error!(ErrorKind::TypeMismatch, "Value is not an object proxy");
+            Err(1)
+        },
+        Err(payload) => {
+            let (kind, msg) = last_error::describe_panic(payload);
+            let kind = last_error::take_raised(&msg).unwrap_or(kind);
+            set_last_error!(kind, "Proxied object failed: {}", msg);
+            Err(1)
+        }
+    }
+}
+
+/// Writes 1 to `ret_place` if the pinned proxy `v` has the field `name`,
+/// either as a static field or according to `on_has_field`.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_has_field(
+    ret_place: *mut u32,
+    v: &Value,
+    e: &ExecutorImpl,
+    name: *const c_char
+) -> i32 {
+    let name = match unsafe { read_c_str(name) } {
+        Some(v) => v,
+        None => return 1
+    };
+    match with_proxy(v, e, |p| p.has_field(name)) {
+        Ok(has) => {
+            write_place(ret_place, if has { 1 } else { 0 });
+            0
+        },
+        Err(err) => err
+    }
+}
+
+/// Deletes the field `name` of the pinned proxy `v`. Fails for const
+/// fields and frozen proxies.
+#[no_mangle]
+pub extern "C" fn hexagon_ort_object_proxy_delete_field(
+    v: &Value,
+    e: &ExecutorImpl,
+    name: *const c_char
+) -> i32 {
+    let name = match unsafe { read_c_str(name) } {
+        Some(v) => v,
+        None => return 1
+    };
+    match with_proxy(v, e, |p| p.delete_field(name)) {
+        Ok(_) => 0,
+        Err(err) => err
     }
 }
 
+/// Calls `cb` on each field name of the pinned proxy `v` until it returns
+/// non-zero. Static fields are merged with the ones reported by
+/// `on_enumerate_fields`.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_function_dump_json(
-    f: &Function
-) -> *mut c_char {
-    if let Some(v) = f.to_virtual_info() {
-        if let Ok(v) = serde_json::to_string(&v) {
-            CString::new(v).unwrap().into_raw()
-        } else {
-            null_mut()
+pub extern "C" fn hexagon_ort_object_proxy_enumerate_fields(
+    v: &Value,
+    e: &ExecutorImpl,
+    cb: object_proxy::FieldVisitor,
+    user_data: *const ()
+) -> i32 {
+    let names = match with_proxy(v, e, |p| p.field_names()) {
+        Ok(v) => v,
+        Err(err) => return err
+    };
+    for name in names.iter() {
+        if cb(name.as_ptr(), name.len() as u32, user_data) != 0 {
+            break;
         }
-    } else {
-        null_mut()
     }
+    0
 }
 
+/// Lets the host report values kept alive by the proxy data, so that the
+/// collector does not free them.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_function_debug_print(
-    f: &Function
+pub extern "C" fn hexagon_ort_object_proxy_set_on_trace(
+    p: &mut ObjectProxy,
+    f: Option<object_proxy::OnTrace>
 ) {
-    if let Some(v) = f.to_virtual_info() {
-        eprintln!("{:?}", v);
-    } else {
-        eprintln!("(not printable)");
-    }
+    p.on_trace = f;
 }
 
+/// Sets the methods of the proxy from `defs`, which is only read during
+/// the call. They become static fields when the proxy is pinned, and are
+/// left out of field enumeration. Replaces methods set earlier.
+///
+/// Proxies given equal definitions share one table of method objects in
+/// each executor, so a host class can pass the same array for each of
+/// its instances. A method only accepts `this` from its own table.
+///
+/// Returns 0 on success, otherwise 1 with the reason in the last error.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_function_bind_this(
-    f: &Function,
-    v: &Value
+pub extern "C" fn hexagon_ort_object_proxy_set_methods(
+    p: &mut ObjectProxy,
+    defs: *const methods::MethodDef,
+    n: u32
 ) -> i32 {
-    if let Err(_) = catch_unwind(AssertUnwindSafe(|| f.bind_this(*v))) {
-        1
+    if defs.is_null() && n != 0 {
+        set_last_error!(ErrorKind::InvalidArgument, "Null method definitions with length {}", n);
+        return 1;
+    }
+    let defs: &[methods::MethodDef] = if n == 0 {
+        &[]
     } else {
-        0
+        unsafe { ::std::slice::from_raw_parts(defs, n as usize) }
+    };
+
+    match ffi_guard(None, || methods::read_defs(defs)) {
+        Some(specs) => {
+            p.methods = Some(specs);
+            0
+        },
+        None => 1
     }
 }
 
-#[no_mangle]
-pub extern "C" fn hexagon_ort_value_create_from_null(ret_place: *mut Value) {
-    write_place(ret_place, Value::Null)
-}
+pub type ArrayVisitor = extern "C" fn (index: u32, value: *const Value, user_data: *const ()) -> i32;
+pub type MapVisitor = extern "C" fn (key: *const u8, key_len: u32, value: *const Value, user_data: *const ()) -> i32;
 
-#[no_mangle]
-pub extern "C" fn hexagon_ort_value_create_from_bool(ret_place: *mut Value, v: u32) {
-    write_place(ret_place, Value::Bool(if v == 0 { false } else { true }))
+fn not_an_array() -> i32 {
+    set_last_error!(ErrorKind::TypeMismatch, "Value is not an array");
+    1
 }
 
-#[no_mangle]
-pub extern "C" fn hexagon_ort_value_create_from_i64(ret_place: *mut Value, v: i64) {
-    write_place(ret_place, Value::Int(v))
+fn not_a_map() -> i32 {
+    set_last_error!(ErrorKind::TypeMismatch, "Value is not a map");
+    1
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_create_from_f64(ret_place: *mut Value, v: f64) {
-    write_place(ret_place, Value::Float(v))
+pub extern "C" fn hexagon_ort_array_create(
+    ret_place: *mut Value,
+    e: &mut ExecutorImpl
+) -> i32 {
+    allocate_into(ret_place, e, Box::new(Array::new()))
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_create_from_string(ret_place: *mut Value, v: *const c_char, e: &mut ExecutorImpl) {
-    let id = e.get_object_pool_mut().allocate(Box::new(unsafe { CStr::from_ptr(v).to_str().unwrap() }.to_string()));
-    write_place(ret_place, Value::Object(id))
+pub extern "C" fn hexagon_ort_array_len(
+    ret_place: *mut u32,
+    v: &Value,
+    e: &ExecutorImpl
+) -> i32 {
+    ffi_guard(1, || match with_object(v, e, |a: &Array| a.elements.borrow().len()) {
+        Some(n) => match len_u32(n) {
+            Some(n) => {
+                write_place(ret_place, n);
+                0
+            },
+            None => 1
+        },
+        None => not_an_array()
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_read_i64(ret_place: *mut i64, v: &Value) -> i32 {
-    match *v {
-        Value::Int(v) => {
-            write_place(ret_place, v);
-            0
-        },
-        _ => 1
-    }
+pub extern "C" fn hexagon_ort_array_push(
+    v: &Value,
+    e: &ExecutorImpl,
+    item: &Value
+) -> i32 {
+    ffi_guard(1, || match with_object(v, e, |a: &Array| a.elements.borrow_mut().push(*item)) {
+        Some(_) => 0,
+        None => not_an_array()
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_read_f64(ret_place: *mut f64, v: &Value) -> i32 {
-    match *v {
-        Value::Float(v) => {
-            write_place(ret_place, v);
+pub extern "C" fn hexagon_ort_array_get(
+    ret_place: *mut Value,
+    v: &Value,
+    e: &ExecutorImpl,
+    index: u32
+) -> i32 {
+    ffi_guard(1, || match with_object(v, e, |a: &Array| a.elements.borrow().get(index as usize).cloned()) {
+        Some(Some(item)) => {
+            write_place(ret_place, item);
             0
         },
-        _ => 1
-    }
-}
-
-#[no_mangle]
-pub extern "C" fn hexagon_ort_value_read_null(v: &Value) -> i32 {
-    match *v {
-        Value::Null => 0,
-        _ => 1
-    }
+        Some(None) => {
+            set_last_error!(ErrorKind::InvalidArgument, "Array index out of bound: {}", index);
+            1
+        },
+        None => not_an_array()
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_read_bool(ret_place: *mut i32, v: &Value) -> i32 {
-    match *v {
-        Value::Bool(v) => {
-            write_place(ret_place, if v { 1 } else { 0 });
-            0
+pub extern "C" fn hexagon_ort_array_set(
+    v: &Value,
+    e: &ExecutorImpl,
+    index: u32,
+    item: &Value
+) -> i32 {
+    ffi_guard(1, || match with_object(v, e, |a: &Array| {
+        match a.elements.borrow_mut().get_mut(index as usize) {
+            Some(place) => {
+                *place = *item;
+                true
+            },
+            None => false
+        }
+    }) {
+        Some(true) => 0,
+        Some(false) => {
+            set_last_error!(ErrorKind::InvalidArgument, "Array index out of bound: {}", index);
+            1
         },
-        _ => 1
-    }
+        None => not_an_array()
+    })
 }
 
+/// Calls `cb` on each element in order until it returns non-zero.
+///
+/// The elements are snapshotted before iterating, so the callback
+/// may modify the array.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_get_type(v: &Value) -> u8 {
-    match *v {
-        Value::Bool(_) => b'B',
-        Value::Float(_) => b'F',
-        Value::Int(_) => b'I',
-        Value::Null => b'N',
-        Value::Object(_) => b'O'
+pub extern "C" fn hexagon_ort_array_iterate(
+    v: &Value,
+    e: &ExecutorImpl,
+    cb: ArrayVisitor,
+    user_data: *const ()
+) -> i32 {
+    let elements = match ffi_guard(None, || with_object(v, e, |a: &Array| a.elements.borrow().clone())) {
+        Some(v) => v,
+        None => return not_an_array()
+    };
+    if len_u32(elements.len()).is_none() {
+        return 1;
     }
+    for (i, item) in elements.iter().enumerate() {
+        if cb(i as u32, item, user_data) != 0 {
+            break;
+        }
+    }
+    0
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_read_string(v: &Value, executor: &ExecutorImpl) -> *mut c_char {
-    catch_unwind(AssertUnwindSafe(|| CString::new(ValueContext::new(
-        v,
-        &executor.get_object_pool()
-    ).to_str().as_ref()).unwrap().into_raw())).unwrap_or(null_mut())
+pub extern "C" fn hexagon_ort_map_create(
+    ret_place: *mut Value,
+    e: &mut ExecutorImpl
+) -> i32 {
+    allocate_into(ret_place, e, Box::new(Map::new()))
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_is_string(v: &Value, executor: &ExecutorImpl) -> u32 {
-    if !v.is_object() {
-        return 0;
-    }
-
-    let ctx = ValueContext::new(v, executor.get_object_pool());
-    match ctx.as_object_direct().as_any().downcast_ref::<String>() {
-        Some(_) => 1,
-        None => 0
-    }
+pub extern "C" fn hexagon_ort_map_len(
+    ret_place: *mut u32,
+    v: &Value,
+    e: &ExecutorImpl
+) -> i32 {
+    ffi_guard(1, || match with_object(v, e, |m: &Map| m.entries.borrow().len()) {
+        Some(n) => match len_u32(n) {
+            Some(n) => {
+                write_place(ret_place, n);
+                0
+            },
+            None => 1
+        },
+        None => not_a_map()
+    })
 }
 
+/// Keys are passed with their length, as in `hexagon_ort_map_iterate`,
+/// and must be valid UTF-8.
+///
+/// Removes the entry if `item` is null.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_value_to_object_handle<'a>(v: &Value, executor: &'a ExecutorImpl) -> *mut ObjectHandle<'a> {
-    if let Value::Object(id) = *v {
-        Box::into_raw(Box::new(executor.get_object_pool().get(id)))
-    } else {
-        null_mut()
-    }
+pub extern "C" fn hexagon_ort_map_set(
+    v: &Value,
+    e: &ExecutorImpl,
+    key: *const u8,
+    key_len: u32,
+    item: *const Value
+) -> i32 {
+    let key = match unsafe { read_str_len(key, key_len) } {
+        Some(v) => v,
+        None => return 1
+    };
+    ffi_guard(1, || match with_object(v, e, |m: &Map| {
+        if item.is_null() {
+            m.entries.borrow_mut().remove(key);
+        } else {
+            m.entries.borrow_mut().insert(key.to_string(), unsafe { *item });
+        }
+    }) {
+        Some(_) => 0,
+        None => not_a_map()
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_handle_to_object_proxy(handle: &ObjectHandle) -> *const ObjectProxy {
-    match handle.as_any().downcast_ref::<ObjectProxy>() {
+pub extern "C" fn hexagon_ort_map_get(
+    ret_place: *mut Value,
+    v: &Value,
+    e: &ExecutorImpl,
+    key: *const u8,
+    key_len: u32
+) -> i32 {
+    let key = match unsafe { read_str_len(key, key_len) } {
         Some(v) => v,
-        None => null()
-    }
+        None => return 1
+    };
+    ffi_guard(1, || match with_object(v, e, |m: &Map| m.entries.borrow().get(key).cloned()) {
+        Some(Some(item)) => {
+            write_place(ret_place, item);
+            0
+        },
+        Some(None) => {
+            set_last_error!(ErrorKind::InvalidArgument, "Key not found: {}", key);
+            1
+        },
+        None => not_a_map()
+    })
 }
 
+/// Calls `cb` on each entry in key order until it returns non-zero.
+///
+/// Keys are passed with their length since they may contain NULs. Like
+/// `hexagon_ort_array_iterate`, entries are snapshotted before iterating.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_handle_to_function(handle: &ObjectHandle) -> *const Function {
-    match handle.as_any().downcast_ref::<Function>() {
+pub extern "C" fn hexagon_ort_map_iterate(
+    v: &Value,
+    e: &ExecutorImpl,
+    cb: MapVisitor,
+    user_data: *const ()
+) -> i32 {
+    let entries: Vec<(String, Value)> = match ffi_guard(None, || with_object(v, e, |m: &Map| {
+        m.entries.borrow().iter().map(|(k, v)| (k.clone(), *v)).collect()
+    })) {
         Some(v) => v,
-        None => null()
+        None => return not_a_map()
+    };
+    for &(ref k, ref item) in entries.iter() {
+        let len = match len_u32(k.len()) {
+            Some(v) => v,
+            None => return 1
+        };
+        if cb(k.as_ptr(), len, item, user_data) != 0 {
+            break;
+        }
     }
+    0
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn hexagon_ort_object_handle_destroy(h: *mut ObjectHandle) {
-    Box::from_raw(h);
-}
-
-#[no_mangle]
-pub extern "C" fn hexagon_ort_executor_pin_object_proxy(
+pub extern "C" fn hexagon_ort_value_from_json(
     ret_place: *mut Value,
-    e: &mut ExecutorImpl,
-    p: *mut ObjectProxy
-) {
-    let p = unsafe {
-        Box::from_raw(p)
-    };
-    let id = e.get_object_pool_mut().allocate(p);
-    write_place(ret_place, Value::Object(id))
+    executor: &mut ExecutorImpl,
+    json: *const u8,
+    len: u32
+) -> i32 {
+    hexagon_ort_value_from_json_with_depth(ret_place, executor, json, len, serialize::DEFAULT_MAX_DEPTH)
 }
 
+/// Parses a JSON document into a value graph. Objects become maps and
+/// arrays become arrays in the object pool.
+///
+/// Returns 1 if the document is malformed, nested deeper than `max_depth`,
+/// or would put the executor over its memory limit. The limit is checked
+/// as each value is allocated, not after the whole document is built.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_executor_pin_function(
+pub extern "C" fn hexagon_ort_value_from_json_with_depth(
     ret_place: *mut Value,
-    e: &mut ExecutorImpl,
-    f: *mut Function
-) {
-    let f = unsafe {
-        Box::from_raw(f)
+    executor: &mut ExecutorImpl,
+    json: *const u8,
+    len: u32,
+    max_depth: u32
+) -> i32 {
+    let json = match unsafe { read_buffer(json, len) } {
+        Some(v) => v,
+        None => return 1
     };
-    let id = e.get_object_pool_mut().allocate(f);
-    write_place(ret_place, Value::Object(id))
+    ffi_guard(1, || {
+        let out_of_memory = Cell::new(false);
+        let mut de = serde_json::Deserializer::from_slice(json);
+        let seed = DeserializeValue::new(executor, max_depth, &out_of_memory);
+        let v = match ::serde::de::DeserializeSeed::deserialize(seed, &mut de).and_then(|v| de.end().map(|_| v)) {
+            Ok(v) => v,
+            Err(e) => {
+                if !out_of_memory.get() {
+                    set_last_error!(ErrorKind::Decode, "JSON decoding failed: {}", e);
+                }
+                return 1;
+            }
+        };
+        write_place(ret_place, v);
+        0
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_create(data: *const ()) -> *mut ObjectProxy {
-    Box::into_raw(Box::new(ObjectProxy::new(data)))
+pub extern "C" fn hexagon_ort_value_to_json(
+    v: &Value,
+    executor: &ExecutorImpl
+) -> *mut c_char {
+    hexagon_ort_value_to_json_with_depth(v, executor, serialize::DEFAULT_MAX_DEPTH)
 }
 
+/// Serializes a value graph to JSON. The result should be released with
+/// `hexagon_glue_destroy_cstring`.
+///
+/// Returns null if the graph contains a cycle, an object that is not a
+/// string, byte buffer, array or map, or is nested deeper than `max_depth`.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_get_data(
-    p: &ObjectProxy
-) -> *const () {
-    p.data
+pub extern "C" fn hexagon_ort_value_to_json_with_depth(
+    v: &Value,
+    executor: &ExecutorImpl,
+    max_depth: u32
+) -> *mut c_char {
+    ffi_guard(null_mut(), || {
+        let visiting = RefCell::new(HashSet::new());
+        let ser = SerializeValue::new(*v, executor.get_object_pool(), max_depth, &visiting);
+        match serde_json::to_string(&ser) {
+            Ok(v) => CString::new(v).unwrap().into_raw(),
+            Err(e) => {
+                set_last_error!(ErrorKind::Encode, "JSON encoding failed: {}", e);
+                null_mut()
+            }
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn hexagon_ort_object_proxy_destroy(
-    p: *mut ObjectProxy
-) {
-    Box::from_raw(p);
+pub extern "C" fn hexagon_ort_value_from_msgpack(
+    ret_place: *mut Value,
+    executor: &mut ExecutorImpl,
+    data: *const u8,
+    len: u32
+) -> i32 {
+    hexagon_ort_value_from_msgpack_with_depth(ret_place, executor, data, len, serialize::DEFAULT_MAX_DEPTH)
 }
 
+/// Same as `hexagon_ort_value_from_json_with_depth`, but for MessagePack.
+/// `bin` values become byte buffers.
+///
+/// `data` must hold exactly one value. Trailing bytes are rejected.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_freeze(
-    p: &mut ObjectProxy
-) {
-    p.frozen = true;
+pub extern "C" fn hexagon_ort_value_from_msgpack_with_depth(
+    ret_place: *mut Value,
+    executor: &mut ExecutorImpl,
+    data: *const u8,
+    len: u32,
+    max_depth: u32
+) -> i32 {
+    let data = match unsafe { read_buffer(data, len) } {
+        Some(v) => v,
+        None => return 1
+    };
+    ffi_guard(1, || {
+        let out_of_memory = Cell::new(false);
+        let mut rest = data;
+        let v = {
+            let mut de = rmp_serde::Deserializer::new(&mut rest);
+            let seed = DeserializeValue::new(executor, max_depth, &out_of_memory);
+            match ::serde::de::DeserializeSeed::deserialize(seed, &mut de) {
+                Ok(v) => v,
+                Err(e) => {
+                    if !out_of_memory.get() {
+                        set_last_error!(ErrorKind::Decode, "MessagePack decoding failed: {}", e);
+                    }
+                    return 1;
+                }
+            }
+        };
+        if !rest.is_empty() {
+            set_last_error!(ErrorKind::Decode, "MessagePack decoding failed: {} trailing bytes", rest.len());
+            return 1;
+        }
+        write_place(ret_place, v);
+        0
+    })
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_add_const_field(
-    p: &mut ObjectProxy,
-    name: *const c_char
-) {
-    let name = unsafe { CStr::from_ptr(name).to_str().unwrap() }.to_string();
-    p.const_fields.insert(name);
+pub extern "C" fn hexagon_ort_value_to_msgpack(
+    len_place: *mut u32,
+    v: &Value,
+    executor: &ExecutorImpl
+) -> *mut u8 {
+    hexagon_ort_value_to_msgpack_with_depth(len_place, v, executor, serialize::DEFAULT_MAX_DEPTH)
 }
 
+/// Serializes a value graph to MessagePack and writes the length of the
+/// result to `len_place`.
+///
+/// The buffer is allocated with `hexagon_glue_alloc` and should be released
+/// with `hexagon_glue_free`. Fails in the same cases as
+/// `hexagon_ort_value_to_json_with_depth`.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_set_destructor(
-    p: &mut ObjectProxy,
-    f: Option<object_proxy::Destructor>
-) {
-    p.destructor = f;
+pub extern "C" fn hexagon_ort_value_to_msgpack_with_depth(
+    len_place: *mut u32,
+    v: &Value,
+    executor: &ExecutorImpl,
+    max_depth: u32
+) -> *mut u8 {
+    ffi_guard(null_mut(), || {
+        let visiting = RefCell::new(HashSet::new());
+        let ser = SerializeValue::new(*v, executor.get_object_pool(), max_depth, &visiting);
+        let encoded = match rmp_serde::to_vec(&ser) {
+            Ok(v) => v,
+            Err(e) => {
+                set_last_error!(ErrorKind::Encode, "MessagePack encoding failed: {}", e);
+                return null_mut();
+            }
+        };
+        let len = match len_u32(encoded.len()) {
+            Some(v) => v,
+            None => return null_mut()
+        };
+        unsafe {
+            let buf = hexagon_glue_alloc(encoded.len());
+            ::std::ptr::copy_nonoverlapping(encoded.as_ptr(), buf, encoded.len());
+            write_place(len_place, len);
+            buf
+        }
+    })
 }
 
+/// Roots `v` so that it is not collected until the returned handle
+/// is destroyed with `hexagon_ort_handle_destroy`.
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_set_static_field(
-    p: &mut ObjectProxy,
-    k: *const c_char,
-    v: *const Value
-) {
-    let k = unsafe { CStr::from_ptr(k).to_str().unwrap() };
-    if v.is_null() {
-        p.static_fields.remove(k);
-    } else {
-        p.static_fields.insert(k.to_string(), unsafe { *v });
-    }
+pub extern "C" fn hexagon_ort_handle_create(
+    e: &mut ExecutorImpl,
+    v: &Value
+) -> *mut Handle {
+    ffi_guard(null_mut(), || Box::into_raw(Box::new(HandleTable::root(e, *v))))
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_set_on_call(
-    p: &mut ObjectProxy,
-    f: Option<object_proxy::OnCall>
+pub extern "C" fn hexagon_ort_handle_get(
+    ret_place: *mut Value,
+    h: &Handle
 ) {
-    p.on_call = f;
+    write_place(ret_place, h.get())
 }
 
 #[no_mangle]
-pub extern "C" fn hexagon_ort_object_proxy_set_on_get_field(
-    p: &mut ObjectProxy,
-    f: Option<object_proxy::OnGetField>
+pub unsafe extern "C" fn hexagon_ort_handle_destroy(
+    h: *mut Handle
 ) {
-    p.on_get_field = f;
+    ffi_guard((), || {
+        Box::from_raw(h);
+    })
 }
@@ -0,0 +1,53 @@
+use std::any::Any;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::object_pool::ObjectPool;
+use hexagon_vm_core::value::Value;
+use super::exec_state::MemoryCharge;
+
+/// An immutable binary buffer that may contain arbitrary bytes,
+/// including NULs and invalid UTF-8.
+pub struct Bytes {
+    pub(crate) data: Vec<u8>,
+    _charge: Option<MemoryCharge>
+}
+
+impl Bytes {
+    pub fn new(data: Vec<u8>) -> Bytes {
+        Bytes {
+            data: data,
+            _charge: None
+        }
+    }
+
+    pub(crate) fn charged(data: Vec<u8>, charge: MemoryCharge) -> Bytes {
+        Bytes {
+            data: data,
+            _charge: Some(charge)
+        }
+    }
+}
+
+impl Object for Bytes {
+    fn get_children(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn as_any(&self) -> &Any {
+        self as &Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self as &mut Any
+    }
+
+    fn get_field(&self, _pool: &ObjectPool, name: &str) -> Option<Value> {
+        match name {
+            "length" => Some(Value::Int(self.data.len() as i64)),
+            _ => None
+        }
+    }
+
+    fn typename(&self) -> &str {
+        "bytes"
+    }
+}
@@ -0,0 +1,189 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::object_pool::ObjectPool;
+use hexagon_vm_core::value::Value;
+use hexagon_vm_core::errors::VMError;
+
+/// An array of values. Scripts read its elements as fields named by
+/// their index, and its size as `length`.
+pub struct Array {
+    pub(crate) elements: RefCell<Vec<Value>>
+}
+
+impl Array {
+    pub fn new() -> Array {
+        Array {
+            elements: RefCell::new(Vec::new())
+        }
+    }
+}
+
+impl Object for Array {
+    fn get_children(&self) -> Vec<usize> {
+        self.elements.borrow().iter()
+            .filter(|v| v.is_object())
+            .map(|v| v.as_object_id())
+            .collect()
+    }
+
+    fn as_any(&self) -> &Any {
+        self as &Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self as &mut Any
+    }
+
+    fn get_field(&self, _pool: &ObjectPool, name: &str) -> Option<Value> {
+        match name {
+            "length" => Some(Value::Int(self.elements.borrow().len() as i64)),
+            _ => match name.parse::<usize>() {
+                Ok(i) => self.elements.borrow().get(i).cloned(),
+                Err(_) => None
+            }
+        }
+    }
+
+    /// Replaces the element at an index, or appends one if the index is
+    /// the current length.
+    fn set_field(&self, name: &str, value: Value) {
+        let i = match name.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => panic!(VMError::from(format!("Invalid array index: {}", name).as_str()))
+        };
+        let mut elements = self.elements.borrow_mut();
+        if i < elements.len() {
+            elements[i] = value;
+        } else if i == elements.len() {
+            elements.push(value);
+        } else {
+            panic!(VMError::from(format!("Array index out of bound: {}", i).as_str()));
+        }
+    }
+
+    fn typename(&self) -> &str {
+        "array"
+    }
+}
+
+/// A string-keyed map. Keys are kept ordered so that iteration
+/// is deterministic.
+pub struct Map {
+    pub(crate) entries: RefCell<BTreeMap<String, Value>>
+}
+
+impl Map {
+    pub fn new() -> Map {
+        Map {
+            entries: RefCell::new(BTreeMap::new())
+        }
+    }
+}
+
+impl Object for Map {
+    fn get_children(&self) -> Vec<usize> {
+        self.entries.borrow().iter()
+            .map(|(_, v)| v)
+            .filter(|v| v.is_object())
+            .map(|v| v.as_object_id())
+            .collect()
+    }
+
+    fn as_any(&self) -> &Any {
+        self as &Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self as &mut Any
+    }
+
+    fn get_field(&self, _pool: &ObjectPool, name: &str) -> Option<Value> {
+        self.entries.borrow().get(name).cloned()
+    }
+
+    fn set_field(&self, name: &str, value: Value) {
+        self.entries.borrow_mut().insert(name.to_string(), value);
+    }
+
+    fn typename(&self) -> &str {
+        "map"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null;
+    use hexagon_vm_core::executor::ExecutorImpl;
+    use hexagon_vm_core::value::{Value, ValueContext};
+    use ort::api::*;
+    use ort::test_util::{with_executor, expect_int};
+
+    fn script_field(e: &ExecutorImpl, v: &Value, name: &str) -> Option<Value> {
+        let ctx = ValueContext::new(v, e.get_object_pool());
+        ctx.as_object_direct().get_field(e.get_object_pool(), name)
+    }
+
+    extern "C" fn collect_key(key: *const u8, key_len: u32, _: *const Value, user_data: *const ()) -> i32 {
+        let keys = unsafe { &mut *(user_data as *mut Vec<Vec<u8>>) };
+        keys.push(unsafe { ::std::slice::from_raw_parts(key, key_len as usize) }.to_vec());
+        0
+    }
+
+    #[test]
+    fn arrays_are_indexable_by_scripts() {
+        with_executor(|e| {
+            let mut a = Value::Null;
+            assert_eq!(hexagon_ort_array_create(&mut a, e), 0);
+            assert_eq!(hexagon_ort_value_get_type_ex(&a, e), b'A');
+            assert_eq!(hexagon_ort_array_push(&a, e, &Value::Int(1)), 0);
+            assert_eq!(hexagon_ort_array_push(&a, e, &Value::Int(2)), 0);
+
+            expect_int(script_field(e, &a, "length"), 2);
+            expect_int(script_field(e, &a, "1"), 2);
+            assert!(script_field(e, &a, "2").is_none());
+
+            {
+                let ctx = ValueContext::new(&a, e.get_object_pool());
+                ctx.as_object_direct().set_field("0", Value::Int(10));
+                ctx.as_object_direct().set_field("2", Value::Int(3));
+            }
+            let mut n = 0;
+            assert_eq!(hexagon_ort_array_len(&mut n, &a, e), 0);
+            assert_eq!(n, 3);
+            let mut item = Value::Null;
+            assert_eq!(hexagon_ort_array_get(&mut item, &a, e, 0), 0);
+            expect_int(Some(item), 10);
+            assert_eq!(hexagon_ort_array_get(&mut item, &a, e, 3), 1);
+        });
+    }
+
+    #[test]
+    fn map_keys_are_length_delimited() {
+        with_executor(|e| {
+            let mut m = Value::Null;
+            assert_eq!(hexagon_ort_map_create(&mut m, e), 0);
+            assert_eq!(hexagon_ort_value_get_type_ex(&m, e), b'M');
+
+            let key = b"a\0b";
+            assert_eq!(hexagon_ort_map_set(&m, e, key.as_ptr(), 3, &Value::Int(1)), 0);
+            assert_eq!(hexagon_ort_map_set(&m, e, b"c".as_ptr(), 1, &Value::Int(2)), 0);
+            assert_eq!(hexagon_ort_map_set(&m, e, b"\xff".as_ptr(), 1, &Value::Int(3)), 1);
+
+            let mut item = Value::Null;
+            assert_eq!(hexagon_ort_map_get(&mut item, &m, e, key.as_ptr(), 3), 0);
+            expect_int(Some(item), 1);
+            expect_int(script_field(e, &m, "c"), 2);
+
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            assert_eq!(hexagon_ort_map_iterate(&m, e, collect_key, &mut keys as *mut Vec<Vec<u8>> as *const ()), 0);
+            assert_eq!(keys, vec![key.to_vec(), b"c".to_vec()]);
+
+            assert_eq!(hexagon_ort_map_set(&m, e, b"c".as_ptr(), 1, null()), 0);
+            let mut n = 0;
+            assert_eq!(hexagon_ort_map_len(&mut n, &m, e), 0);
+            assert_eq!(n, 1);
+        });
+    }
+}
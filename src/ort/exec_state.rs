@@ -0,0 +1,421 @@
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::Instant;
+use smallvec::SmallVec;
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::value::{Value, ValueContext};
+use super::last_error::{self, ErrorKind};
+use super::interrupt::InterruptHandle;
+use super::handles::Handle;
+use super::bytes::Bytes;
+use super::methods::{MethodSpec, MethodTable};
+
+const STATE_KEY: &'static str = "__hexagon_bridge_state";
+
+/// Rough size of a pool slot. Heap usage is estimated as this times the
+/// live object count, plus the payloads charged with `MemoryCharge`.
+pub const OBJECT_SIZE_ESTIMATE: usize = 64;
+
+/// Payload bytes held by an object allocated through the bridge. They
+/// count towards the memory limit until the object is freed.
+pub struct MemoryCharge {
+    total: Rc<Cell<usize>>,
+    bytes: usize
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.total.set(self.total.get() - self.bytes);
+    }
+}
+
+/// Bridge-side settings for an executor.
+///
+/// `ExecutorImpl` lives in hexagon-vm-core and cannot carry extra fields,
+/// so these are kept in a static object of the executor, like the handle
+/// table. They go away together with the executor.
+pub struct ExecState {
+    pub gc_threshold: usize,
+    pub memory_limit: usize,
+    charged: Rc<Cell<usize>>,
+    pub call_budget: u64,
+    pub calls_left: u64,
+    pub calls_used: u64,
+    pub interrupt: InterruptHandle,
+    pub deadline: Option<Instant>,
+    /// Value thrown by the last failed invocation.
+    pub thrown: Option<Handle>,
+    /// Method tables built so far, by the specs they were built for.
+    pub method_tables: HashMap<Vec<MethodSpec>, Rc<MethodTable>>,
+    depth: usize
+}
+
+impl Default for ExecState {
+    fn default() -> ExecState {
+        ExecState {
+            gc_threshold: 0,
+            memory_limit: 0,
+            charged: Rc::new(Cell::new(0)),
+            call_budget: 0,
+            calls_left: 0,
+            calls_used: 0,
+            interrupt: InterruptHandle::new(),
+            deadline: None,
+            thrown: None,
+            method_tables: HashMap::new(),
+            depth: 0
+        }
+    }
+}
+
+struct StateObject {
+    state: Rc<RefCell<ExecState>>
+}
+
+impl Object for StateObject {
+    fn get_children(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn as_any(&self) -> &Any {
+        self as &Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self as &mut Any
+    }
+}
+
+/// Marks the extent of an invocation started by the host.
+///
+/// Per-invocation budgets are set only when the outermost invocation
+/// starts, so that native code cannot escape them by invoking again. A
+/// nested invocation may only shorten the deadline, and the outer one is
+/// restored when it returns.
+pub struct InvocationGuard {
+    state: Rc<RefCell<ExecState>>,
+    outermost: bool,
+    outer_deadline: Option<Instant>
+}
+
+impl InvocationGuard {
+    pub fn is_outermost(&self) -> bool {
+        self.outermost
+    }
+}
+
+impl Drop for InvocationGuard {
+    fn drop(&mut self) {
+        let mut s = self.state.borrow_mut();
+        s.depth -= 1;
+        s.deadline = self.outer_deadline;
+    }
+}
+
+#[repr(C)]
+pub struct HeapStats {
+    pub live_objects: u64,
+    pub approx_bytes: u64,
+    pub gc_threshold: u64
+}
+
+fn find(e: &ExecutorImpl) -> Option<Rc<RefCell<ExecState>>> {
+    let v: Value = match e.get_static_object(STATE_KEY) {
+        Some(v) => (*v).into(),
+        None => return None
+    };
+    let ctx = ValueContext::new(&v, e.get_object_pool());
+    let ret = ctx.as_object_direct().as_any().downcast_ref::<StateObject>().map(|s| s.state.clone());
+    ret
+}
+
+fn get_or_create(e: &mut ExecutorImpl) -> Rc<RefCell<ExecState>> {
+    match find(e) {
+        Some(v) => v,
+        None => {
+            let state = Rc::new(RefCell::new(ExecState::default()));
+            e.create_static_object(STATE_KEY, Box::new(StateObject {
+                state: state.clone()
+            }));
+            state
+        }
+    }
+}
+
+/// Runs `f` on the state of `e`. If nothing has been configured yet, `f`
+/// sees the defaults and its changes are discarded; use `with_mut` to
+/// change settings.
+pub fn with<R, F: FnOnce(&mut ExecState) -> R>(e: &ExecutorImpl, f: F) -> R {
+    match find(e) {
+        Some(s) => f(&mut s.borrow_mut()),
+        None => f(&mut ExecState::default())
+    }
+}
+
+/// Runs `f` on the state of `e`, creating it first if needed.
+pub fn with_mut<R, F: FnOnce(&mut ExecState) -> R>(e: &mut ExecutorImpl, f: F) -> R {
+    let state = get_or_create(e);
+    let mut s = state.borrow_mut();
+    f(&mut s)
+}
+
+pub fn begin_invocation(e: &mut ExecutorImpl, deadline: Option<Instant>) -> InvocationGuard {
+    let state = get_or_create(e);
+    let (outermost, outer_deadline) = {
+        let mut s = state.borrow_mut();
+        let outermost = s.depth == 0;
+        let outer_deadline = if outermost { None } else { s.deadline };
+        s.deadline = match (outer_deadline, deadline) {
+            (Some(a), Some(b)) => Some(::std::cmp::min(a, b)),
+            (a, b) => a.or(b)
+        };
+        if outermost {
+            last_error::begin_invocation();
+            s.calls_left = s.call_budget;
+            s.calls_used = 0;
+            s.interrupt.reset();
+            s.thrown = None;
+        }
+        s.depth += 1;
+        (outermost, outer_deadline)
+    };
+    InvocationGuard {
+        state: state,
+        outermost: outermost,
+        outer_deadline: outer_deadline
+    }
+}
+
+fn approx_bytes(e: &ExecutorImpl) -> usize {
+    e.get_object_pool().get_alive_count() * OBJECT_SIZE_ESTIMATE + with(e, |s| s.charged.get())
+}
+
+pub fn heap_stats(e: &ExecutorImpl) -> HeapStats {
+    HeapStats {
+        live_objects: e.get_object_pool().get_alive_count() as u64,
+        approx_bytes: approx_bytes(e) as u64,
+        gc_threshold: with(e, |s| s.gc_threshold) as u64
+    }
+}
+
+/// Returns false and records an `OutOfMemory` error if `extra` more bytes
+/// would put the executor over its memory limit.
+fn has_room(e: &ExecutorImpl, extra: usize) -> bool {
+    let limit = with(e, |s| s.memory_limit);
+    if limit != 0 && approx_bytes(e).saturating_add(extra) > limit {
+        set_last_error!(ErrorKind::OutOfMemory, "Memory limit of {} bytes exceeded", limit);
+        false
+    } else {
+        true
+    }
+}
+
+/// Returns false and records an `OutOfMemory` error if the executor
+/// is over its memory limit.
+pub fn check_memory(e: &ExecutorImpl) -> bool {
+    has_room(e, 0)
+}
+
+/// Creates the static object `key` unless the executor would go over its
+/// memory limit.
+pub fn create_static_object(e: &mut ExecutorImpl, key: &str, obj: Box<Object>) -> bool {
+    if has_room(e, OBJECT_SIZE_ESTIMATE) {
+        e.create_static_object(key, obj);
+        true
+    } else {
+        false
+    }
+}
+
+/// Allocates `obj` unless the executor would go over its memory limit.
+///
+/// Garbage is not collected here, since the host may be holding
+/// unrooted values while it builds a new one.
+pub fn allocate(e: &mut ExecutorImpl, obj: Box<Object>) -> Option<usize> {
+    allocate_sized(e, obj, 0)
+}
+
+/// Same as `allocate`, for an object holding `payload` bytes outside its
+/// pool slot.
+///
+/// The payload is only checked against the limit here. Use a type that
+/// keeps a `MemoryCharge`, like `allocate_bytes` does, to keep counting it
+/// while the object lives. Strings are core types and cannot hold one.
+pub fn allocate_sized(e: &mut ExecutorImpl, obj: Box<Object>, payload: usize) -> Option<usize> {
+    if has_room(e, OBJECT_SIZE_ESTIMATE.saturating_add(payload)) {
+        Some(e.get_object_pool_mut().allocate(obj))
+    } else {
+        None
+    }
+}
+
+/// Allocates a byte buffer whose contents count towards the memory limit
+/// for as long as it lives.
+pub fn allocate_bytes(e: &mut ExecutorImpl, data: Vec<u8>) -> Option<usize> {
+    if !has_room(e, OBJECT_SIZE_ESTIMATE.saturating_add(data.len())) {
+        return None;
+    }
+    let total = with_mut(e, |s| s.charged.clone());
+    total.set(total.get() + data.len());
+    let charge = MemoryCharge {
+        total: total,
+        bytes: data.len()
+    };
+    Some(e.get_object_pool_mut().allocate(Box::new(Bytes::charged(data, charge))))
+}
+
+/// Charges `n` calls to the budget of the current invocation. Returns
+/// false and records a `CallBudgetExhausted` error if it is used up, which
+/// a native callback failing afterwards is reported with.
+pub fn charge_calls(e: &ExecutorImpl, n: u64) -> bool {
+    let ok = with(e, |s| {
+        s.calls_used = s.calls_used.saturating_add(n);
+        if s.call_budget == 0 {
+            true
+        } else if s.calls_left < n {
+            s.calls_left = 0;
+            false
+        } else {
+            s.calls_left -= n;
+            true
+        }
+    });
+    if !ok {
+        hit_limit!(ErrorKind::CallBudgetExhausted, "Call budget exhausted");
+    }
+    ok
+}
+
+/// Collects garbage if the executor has reached its gc threshold or is
+/// over its memory limit.
+///
+/// Only call this when nothing outside the VM's own roots (static objects,
+/// the handle table and live frames) refers to a value that is still
+/// needed. In practice that is the start of an outermost invocation, once
+/// its target and arguments have been rooted. Anywhere deeper, a native
+/// function or proxy may be running on an object borrowed from the pool.
+pub fn collect_if_needed(e: &mut ExecutorImpl) {
+    let (threshold, limit) = with(e, |s| (s.gc_threshold, s.memory_limit));
+    let live = e.get_object_pool().get_alive_count();
+
+    if (threshold != 0 && live >= threshold) || (limit != 0 && approx_bytes(e) > limit) {
+        e.gc(true);
+    }
+}
+
+/// Enters a native function called by the VM: runs a safe point, then
+/// reads `this` and the arguments from the current frame.
+pub fn enter_native(e: &mut ExecutorImpl) -> (Value, SmallVec<[Value; 4]>) {
+    safepoint(e);
+
+    let frame = e.get_current_frame();
+    let n_args = frame.get_n_arguments();
+    let args: SmallVec<[Value; 4]> = (0..n_args).map(|i| frame.get_argument(i).unwrap()).collect();
+    (frame.get_this(), args)
+}
+
+/// Called on entry to an invocation, a native function or a proxy call.
+///
+/// Every safe point is charged as one call. The interpreter loop itself
+/// lives in hexagon-vm-core and offers no hook to count instructions, so
+/// script code between calls is not metered.
+///
+/// Raises a VM error if the executor is over its memory limit, if the
+/// call budget is used up, if an interrupt was triggered or if the
+/// deadline has passed. Garbage is never collected here.
+pub fn safepoint(e: &mut ExecutorImpl) {
+    let (interrupted, deadline) = with(e, |s| (s.interrupt.take(), s.deadline));
+    if interrupted {
+        raise!(ErrorKind::Interrupted, "Interrupted");
+    }
+    if let Some(deadline) = deadline {
+        if Instant::now() >= deadline {
+            raise!(ErrorKind::Timeout, "Timed out");
+        }
+    }
+
+    if !check_memory(e) {
+        raise!(ErrorKind::OutOfMemory, "Out of memory");
+    }
+    if !charge_calls(e, 1) {
+        raise!(ErrorKind::CallBudgetExhausted, "Call budget exhausted");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::{null, null_mut};
+    use hexagon_vm_core::value::Value;
+    use ort::api::*;
+    use ort::last_error::{self, ErrorKind};
+    use ort::test_util::{c_str, with_executor};
+    use super::HeapStats;
+
+    fn approx_bytes(e: &::hexagon_vm_core::executor::ExecutorImpl) -> u64 {
+        let mut stats = unsafe { ::std::mem::zeroed::<HeapStats>() };
+        assert_eq!(hexagon_ort_executor_impl_get_heap_stats(&mut stats, e), 0);
+        stats.approx_bytes
+    }
+
+    #[test]
+    fn payloads_count_towards_the_memory_limit() {
+        with_executor(|e| {
+            hexagon_ort_executor_impl_set_memory_limit(e, 4096);
+            let data = vec![b'x'; 8192];
+            let mut v = Value::Null;
+
+            assert_eq!(hexagon_ort_value_create_from_str_len(&mut v, data.as_ptr(), 8192, e), 1);
+            assert_eq!(last_error::kind(), ErrorKind::OutOfMemory);
+
+            let before = approx_bytes(e);
+            assert_eq!(hexagon_ort_value_create_from_bytes(&mut v, data.as_ptr(), 2048, e), 0);
+            assert!(approx_bytes(e) >= before + 2048);
+            assert_eq!(hexagon_ort_value_create_from_bytes(&mut v, data.as_ptr(), 2048, e), 1);
+            assert_eq!(last_error::kind(), ErrorKind::OutOfMemory);
+
+            // The buffer is not rooted, so collecting releases its charge.
+            assert_eq!(hexagon_ort_executor_impl_gc(e), 0);
+            assert_eq!(approx_bytes(e), before);
+            assert_eq!(hexagon_ort_value_create_from_bytes(&mut v, data.as_ptr(), 2048, e), 0);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn parsing_stops_at_the_memory_limit() {
+        with_executor(|e| {
+            hexagon_ort_executor_impl_set_memory_limit(e, 1024);
+            let json = format!("[{}\"x\"]", "\"x\",".repeat(100));
+            let mut v = Value::Null;
+            assert_eq!(hexagon_ort_value_from_json(&mut v, e, json.as_ptr(), json.len() as u32), 1);
+            assert_eq!(last_error::kind(), ErrorKind::OutOfMemory);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn invocations_over_the_limit_report_out_of_memory() {
+        with_executor(|e| {
+            let mut handles = Vec::new();
+            for _ in 0..16 {
+                let mut v = Value::Null;
+                assert_eq!(hexagon_ort_value_create_from_string(&mut v, c_str(b"x\0"), e), 0);
+                handles.push(hexagon_ort_handle_create(e, &v));
+            }
+            hexagon_ort_executor_impl_set_memory_limit(e, 256);
+
+            let mut ret = Value::Null;
+            let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &Value::Null, null(), null(), 0);
+            assert_eq!(status, InvokeStatus::OutOfMemory as u32);
+            assert_eq!(last_error::kind(), ErrorKind::OutOfMemory);
+
+            for h in handles {
+                unsafe { hexagon_ort_handle_destroy(h); }
+            }
+            last_error::clear();
+        });
+    }
+}
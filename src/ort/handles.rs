@@ -0,0 +1,159 @@
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::RefCell;
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::value::{Value, ValueContext};
+
+const HANDLE_TABLE_KEY: &'static str = "__hexagon_bridge_handles";
+
+struct Slots {
+    values: Vec<Option<Value>>,
+    free: Vec<usize>
+}
+
+/// Keeps host-held values alive.
+///
+/// The table is stored as a static object of the executor, which the
+/// collector always treats as a root, and reports every rooted value
+/// as one of its children.
+pub struct HandleTable {
+    slots: Rc<RefCell<Slots>>
+}
+
+/// A persistent reference to a value that is never collected until
+/// the handle is dropped.
+pub struct Handle {
+    slots: Rc<RefCell<Slots>>,
+    index: usize
+}
+
+impl HandleTable {
+    fn new() -> HandleTable {
+        HandleTable {
+            slots: Rc::new(RefCell::new(Slots {
+                values: Vec::new(),
+                free: Vec::new()
+            }))
+        }
+    }
+
+    fn find(e: &ExecutorImpl) -> Option<Rc<RefCell<Slots>>> {
+        let v: Value = match e.get_static_object(HANDLE_TABLE_KEY) {
+            Some(v) => (*v).into(),
+            None => return None
+        };
+        let ctx = ValueContext::new(&v, e.get_object_pool());
+        let ret = ctx.as_object_direct().as_any().downcast_ref::<HandleTable>().map(|t| t.slots.clone());
+        ret
+    }
+
+    pub fn root(e: &mut ExecutorImpl, value: Value) -> Handle {
+        let slots = match HandleTable::find(e) {
+            Some(v) => v,
+            None => {
+                let table = HandleTable::new();
+                let slots = table.slots.clone();
+                e.create_static_object(HANDLE_TABLE_KEY, Box::new(table));
+                slots
+            }
+        };
+
+        let index = {
+            let mut s = slots.borrow_mut();
+            match s.free.pop() {
+                Some(i) => {
+                    s.values[i] = Some(value);
+                    i
+                },
+                None => {
+                    s.values.push(Some(value));
+                    s.values.len() - 1
+                }
+            }
+        };
+
+        Handle {
+            slots: slots,
+            index: index
+        }
+    }
+}
+
+impl Handle {
+    pub fn get(&self) -> Value {
+        self.slots.borrow().values[self.index].unwrap()
+    }
+
+    /// Whether the handle was created by `e`.
+    pub fn belongs_to(&self, e: &ExecutorImpl) -> bool {
+        match HandleTable::find(e) {
+            Some(slots) => Rc::ptr_eq(&slots, &self.slots),
+            None => false
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let mut s = self.slots.borrow_mut();
+        s.values[self.index] = None;
+        s.free.push(self.index);
+    }
+}
+
+impl Object for HandleTable {
+    fn get_children(&self) -> Vec<usize> {
+        self.slots.borrow().values.iter()
+            .filter_map(|v| *v)
+            .filter(|v| v.is_object())
+            .map(|v| v.as_object_id())
+            .collect()
+    }
+
+    fn as_any(&self) -> &Any {
+        self as &Any
+    }
+
+    fn as_any_mut(&mut self) -> &mut Any {
+        self as &mut Any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use hexagon_vm_core::executor::Executor;
+    use hexagon_vm_core::value::Value;
+    use ort::api::*;
+    use ort::test_util::pin_counted_proxy;
+
+    static ROOTED_DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+    static UNROOTED_DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    #[test]
+    fn rooted_values_survive_collection() {
+        let mut executor = Executor::new();
+        let e = unsafe { &mut *hexagon_ort_executor_get_impl(&mut executor) };
+
+        let rooted = pin_counted_proxy(e, &ROOTED_DROPS);
+        pin_counted_proxy(e, &UNROOTED_DROPS);
+
+        let h = hexagon_ort_handle_create(e, &rooted);
+        assert!(!h.is_null());
+
+        e.gc(true);
+        assert_eq!(ROOTED_DROPS.load(Ordering::SeqCst), 0);
+        assert_eq!(UNROOTED_DROPS.load(Ordering::SeqCst), 1);
+
+        let mut v = Value::Null;
+        unsafe {
+            hexagon_ort_handle_get(&mut v, &*h);
+        }
+        assert_eq!(v.as_object_id(), rooted.as_object_id());
+
+        unsafe { hexagon_ort_handle_destroy(h); }
+        e.gc(true);
+        assert_eq!(ROOTED_DROPS.load(Ordering::SeqCst), 1);
+    }
+}
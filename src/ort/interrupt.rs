@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A flag that another thread can raise to stop a running script.
+///
+/// Interrupts are delivered at the next safe point, and a pending one is
+/// discarded when a new top-level run starts, so that a trigger arriving
+/// after a run has finished does not stop the next one. Safe points are
+/// host calls: hexagon-vm-core does not poll the flag, so a script looping
+/// without calling the host is not interrupted.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>
+}
+
+impl InterruptHandle {
+    pub fn new() -> InterruptHandle {
+        InterruptHandle {
+            flag: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns whether an interrupt was pending, clearing it.
+    pub fn take(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,120 @@
+use std::ptr::null;
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::value::Value;
+use super::api::*;
+use super::last_error::{self, ErrorKind};
+use super::test_util::{c_str, with_executor};
+
+const INVALID_UTF8: &'static [u8] = b"\xff\xfe\xfd\0";
+const TRUNCATED_UTF8: &'static [u8] = b"key\xe4\xbd\0";
+
+extern "C" fn return_null(ret_place: *mut Value, _: &mut ExecutorImpl, _: *const ()) -> i32 {
+    unsafe { *ret_place = Value::Null; }
+    0
+}
+
+fn expect_error<T: PartialEq + ::std::fmt::Debug>(ret: T, expected: T, kind: ErrorKind) {
+    assert_eq!(ret, expected);
+    assert_eq!(last_error::kind(), kind);
+    last_error::clear();
+}
+
+#[test]
+fn attach_function_with_malformed_key() {
+    with_executor(|e| {
+        for key in &[INVALID_UTF8, TRUNCATED_UTF8] {
+            let f = hexagon_ort_function_load_native(return_null, None, null());
+            expect_error(hexagon_ort_executor_impl_attach_function(e, c_str(key), f), 1, ErrorKind::InvalidUtf8);
+        }
+
+        let f = hexagon_ort_function_load_native(return_null, None, null());
+        expect_error(hexagon_ort_executor_impl_attach_function(e, null(), f), 1, ErrorKind::InvalidArgument);
+    });
+}
+
+#[test]
+fn run_callable_with_malformed_key() {
+    with_executor(|e| {
+        expect_error(hexagon_ort_executor_impl_run_callable(e, c_str(INVALID_UTF8)), 1, ErrorKind::InvalidUtf8);
+        expect_error(hexagon_ort_executor_impl_run_callable(e, c_str(TRUNCATED_UTF8)), 1, ErrorKind::InvalidUtf8);
+    });
+}
+
+#[test]
+fn get_static_object_with_malformed_key() {
+    with_executor(|e| {
+        let mut ret = Value::Bool(true);
+        expect_error(hexagon_ort_executor_impl_get_static_object(&mut ret, e, c_str(INVALID_UTF8)), 1, ErrorKind::InvalidUtf8);
+        assert_eq!(hexagon_ort_value_read_null(&ret), 0);
+    });
+}
+
+#[test]
+fn create_string_from_malformed_bytes() {
+    with_executor(|e| {
+        let mut ret = Value::Null;
+        expect_error(hexagon_ort_value_create_from_string(&mut ret, c_str(INVALID_UTF8), e), 1, ErrorKind::InvalidUtf8);
+        expect_error(hexagon_ort_value_create_from_string(&mut ret, null(), e), 1, ErrorKind::InvalidArgument);
+        assert_eq!(hexagon_ort_value_create_from_string(&mut ret, c_str(b"ok\0"), e), 0);
+        assert_eq!(hexagon_ort_value_is_string(&ret, e), 1);
+    });
+}
+
+#[test]
+fn object_proxy_with_malformed_field_names() {
+    let p = hexagon_ort_object_proxy_create(null());
+    let v = Value::Int(42);
+
+    unsafe {
+        expect_error(hexagon_ort_object_proxy_add_const_field(&mut *p, c_str(INVALID_UTF8)), 1, ErrorKind::InvalidUtf8);
+        expect_error(hexagon_ort_object_proxy_set_static_field(&mut *p, c_str(TRUNCATED_UTF8), &v), 1, ErrorKind::InvalidUtf8);
+        assert_eq!(hexagon_ort_object_proxy_set_static_field(&mut *p, c_str(b"answer\0"), &v), 0);
+        hexagon_ort_object_proxy_destroy(p);
+    }
+}
+
+#[test]
+fn load_virtual_with_malformed_input() {
+    let code = b"\xff\xff";
+    assert!(hexagon_ort_function_load_virtual(c_str(INVALID_UTF8), code.as_ptr(), 2).is_null());
+    assert_eq!(last_error::kind(), ErrorKind::InvalidUtf8);
+
+    assert!(hexagon_ort_function_load_virtual(c_str(b"json\0"), code.as_ptr(), 2).is_null());
+    assert_eq!(last_error::kind(), ErrorKind::InvalidUtf8);
+
+    assert!(hexagon_ort_function_load_virtual(c_str(b"msgpack\0"), null(), 2).is_null());
+    assert_eq!(last_error::kind(), ErrorKind::InvalidArgument);
+    last_error::clear();
+}
+
+#[test]
+fn lengths_beyond_u32_are_rejected() {
+    assert_eq!(len_u32(::std::u32::MAX as usize), Some(::std::u32::MAX));
+    if ::std::mem::size_of::<usize>() > 4 {
+        assert_eq!(len_u32(::std::u32::MAX as usize + 1), None);
+        assert_eq!(last_error::kind(), ErrorKind::Unsupported);
+        last_error::clear();
+    }
+}
+
+#[test]
+fn length_delimited_values_round_trip() {
+    with_executor(|e| {
+        let data = b"a\0b\xff";
+        let mut v = Value::Null;
+        let mut len = 0;
+
+        assert_eq!(hexagon_ort_value_create_from_str_len(&mut v, data.as_ptr(), 3, e), 0);
+        let ptr = hexagon_ort_value_read_str(&mut len, &v, e);
+        assert_eq!(unsafe { ::std::slice::from_raw_parts(ptr, len as usize) }, &data[..3]);
+        expect_error(hexagon_ort_value_read_bytes(&mut len, &v, e), null(), ErrorKind::TypeMismatch);
+
+        expect_error(hexagon_ort_value_create_from_str_len(&mut v, data.as_ptr(), 4, e), 1, ErrorKind::InvalidUtf8);
+        expect_error(hexagon_ort_value_create_from_bytes(&mut v, null(), 4, e), 1, ErrorKind::InvalidArgument);
+
+        assert_eq!(hexagon_ort_value_create_from_bytes(&mut v, data.as_ptr(), 4, e), 0);
+        let ptr = hexagon_ort_value_read_bytes(&mut len, &v, e);
+        assert_eq!(unsafe { ::std::slice::from_raw_parts(ptr, len as usize) }, &data[..]);
+        assert_eq!(hexagon_ort_value_is_string(&v, e), 0);
+    });
+}
@@ -0,0 +1,541 @@
+use std::os::raw::c_char;
+use std::ffi::CStr;
+use std::ptr::{null, null_mut};
+use std::panic::catch_unwind;
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::function::Function;
+use hexagon_vm_core::value::Value;
+use super::api::*;
+use super::exec_state::HeapStats;
+use super::interrupt::InterruptHandle;
+use super::last_error::{self, ErrorKind};
+use glue::{hexagon_glue_alloc, hexagon_glue_destroy_cstring};
+use super::test_util::{c_str, with_executor};
+
+extern "C" fn throw_message(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    _: *const ()
+) -> i32 {
+    assert_eq!(hexagon_ort_executor_impl_throw(e, c_str(b"boom\0")), 0);
+    1
+}
+
+extern "C" fn throw_int(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    _: *const ()
+) -> i32 {
+    assert_eq!(hexagon_ort_executor_impl_throw_value(e, &Value::Int(42)), 0);
+    1
+}
+
+extern "C" fn throw_map(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    _: *const ()
+) -> i32 {
+    let mut m = Value::Null;
+    assert_eq!(hexagon_ort_map_create(&mut m, e), 0);
+    assert_eq!(hexagon_ort_executor_impl_throw_value(e, &m), 0);
+    1
+}
+
+extern "C" fn return_null(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    _: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    _: *const ()
+) -> i32 {
+    0
+}
+
+fn pin(e: &mut ExecutorImpl, f: *mut Function) -> Value {
+    let mut v = Value::Null;
+    assert_eq!(hexagon_ort_executor_pin_function(&mut v, e, f), 0);
+    v
+}
+
+fn invoke(e: &mut ExecutorImpl, target: &Value, args: &[Value]) -> u32 {
+    let mut ret = Value::Null;
+    hexagon_ort_executor_impl_invoke_checked(
+        &mut ret,
+        null_mut(),
+        e,
+        target,
+        null(),
+        if args.len() > 0 { &args[0] } else { null() },
+        args.len() as u32
+    )
+}
+
+#[test]
+fn native_errors_are_reported_as_native_errors() {
+    with_executor(|e| {
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_message, None, null()));
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::NativeError as u32);
+        assert_eq!(last_error::kind(), ErrorKind::NativeError);
+        last_error::clear();
+    });
+}
+
+/// Catches an error raised by the bridge, as a script could, and then
+/// invokes the function in `user_data` with no arguments.
+extern "C" fn catch_then_invoke(
+    ret_place: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    assert!(catch_unwind(|| raise!(ErrorKind::NativeError, "caught")).is_err());
+
+    let target = unsafe { &*(user_data as *const Value) };
+    let status = invoke(e, target, &[]);
+    unsafe { *ret_place = Value::Int(status as i64); }
+    0
+}
+
+#[test]
+fn raised_errors_are_matched_exactly() {
+    assert!(catch_unwind(|| raise!(ErrorKind::NativeError, "")).is_err());
+    assert_eq!(last_error::take_raised("Some other error"), None);
+
+    let payload = catch_unwind(|| raise!(ErrorKind::Unsupported, "Not here")).unwrap_err();
+    let (_, msg) = last_error::describe_panic(payload);
+    assert_eq!(last_error::take_raised(&msg), Some(ErrorKind::Unsupported));
+    last_error::clear();
+}
+
+#[test]
+fn caught_native_errors_do_not_mask_vm_errors() {
+    with_executor(|e| {
+        let typed = hexagon_ort_function_load_native_typed(return_null, c_str(b"I\0"), None, null());
+        let typed = pin(e, typed);
+        let f = hexagon_ort_function_load_native_ex(catch_then_invoke, None, &typed as *const Value as *const ());
+        let f = pin(e, f);
+
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        assert_eq!(hexagon_ort_value_get_type_ex(&ret, e), b'I');
+        let mut nested = 0;
+        assert_eq!(hexagon_ort_value_read_i64(&mut nested, &ret), 0);
+        assert_eq!(nested, InvokeStatus::VMError as i64);
+        last_error::clear();
+    });
+}
+
+#[test]
+fn invoke_catch_returns_the_error_value() {
+    with_executor(|e| {
+        let mut ret = Value::Null;
+        let mut err = Value::Null;
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_int, None, null()));
+        let status = hexagon_ort_executor_impl_invoke_catch(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::NativeError as u32);
+        assert_eq!(hexagon_ort_value_get_type_ex(&err, e), b'I');
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_message, None, null()));
+        let status = hexagon_ort_executor_impl_invoke_catch(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::NativeError as u32);
+        let mut len = 0;
+        let s = hexagon_ort_value_read_str(&mut len, &err, e);
+        assert_eq!(unsafe { ::std::slice::from_raw_parts(s, len as usize) }, b"boom");
+        last_error::clear();
+    });
+}
+
+#[test]
+fn values_without_a_string_form_are_thrown_by_type_name() {
+    with_executor(|e| {
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_map, None, null()));
+        let mut ret = Value::Null;
+        let mut err: *mut c_char = null_mut();
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::NativeError as u32);
+        assert!(unsafe { CStr::from_ptr(err) }.to_str().unwrap().contains("map"));
+        unsafe { hexagon_glue_destroy_cstring(err); }
+
+        assert_eq!(hexagon_ort_executor_impl_get_thrown_value(&mut ret, e), 0);
+        assert_eq!(hexagon_ort_value_get_type_ex(&ret, e), b'M');
+        last_error::clear();
+    });
+}
+
+extern "C" fn string_len(
+    ret_place: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    args: *const Value,
+    n_args: u32,
+    _: *const ()
+) -> i32 {
+    assert_eq!(n_args, 1);
+    let mut len = 0;
+    let s = hexagon_ort_value_read_str(&mut len, unsafe { &*args }, e);
+    assert!(!s.is_null());
+    unsafe { *ret_place = Value::Int(len as i64); }
+    0
+}
+
+#[test]
+fn unrooted_arguments_survive_collection() {
+    with_executor(|e| {
+        hexagon_ort_executor_impl_set_gc_threshold(e, 1);
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(string_len, None, null()));
+        let mut s = Value::Null;
+        assert_eq!(hexagon_ort_value_create_from_string(&mut s, c_str(b"hello\0"), e), 0);
+
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &f, null(), &s, 1);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        let mut n = 0;
+        assert_eq!(hexagon_ort_value_read_i64(&mut n, &ret), 0);
+        assert_eq!(n, 5);
+
+        // Nothing roots them once the invocation is over.
+        let mut before = unsafe { ::std::mem::zeroed::<HeapStats>() };
+        assert_eq!(hexagon_ort_executor_impl_get_heap_stats(&mut before, e), 0);
+        assert_eq!(hexagon_ort_executor_impl_gc(e), 0);
+        let mut after = unsafe { ::std::mem::zeroed::<HeapStats>() };
+        assert_eq!(hexagon_ort_executor_impl_get_heap_stats(&mut after, e), 0);
+        assert_eq!(after.live_objects + 2, before.live_objects);
+    });
+}
+
+fn gc_threshold(e: &ExecutorImpl) -> u64 {
+    let mut stats = unsafe { ::std::mem::zeroed::<HeapStats>() };
+    assert_eq!(hexagon_ort_executor_impl_get_heap_stats(&mut stats, e), 0);
+    stats.gc_threshold
+}
+
+#[test]
+fn executors_do_not_share_state() {
+    with_executor(|a| {
+        hexagon_ort_executor_impl_set_gc_threshold(a, 100);
+        with_executor(|b| {
+            assert_eq!(gc_threshold(b), 0);
+            hexagon_ort_executor_impl_set_gc_threshold(b, 200);
+        });
+        assert_eq!(gc_threshold(a), 100);
+    });
+
+    // Likely to reuse the addresses of the executors above.
+    with_executor(|e| assert_eq!(gc_threshold(e), 0));
+}
+
+/// Invokes the function in `user_data`, which is itself, until that fails.
+extern "C" fn recurse(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    let target = unsafe { &*(user_data as *const Value) };
+    if invoke(e, target, &[]) == InvokeStatus::Ok as u32 {
+        0
+    } else {
+        1
+    }
+}
+
+#[test]
+fn runaway_recursion_exhausts_the_call_budget() {
+    with_executor(|e| {
+        hexagon_ort_executor_impl_set_call_budget(e, 100);
+
+        let mut f = Value::Null;
+        let native = hexagon_ort_function_load_native_ex(recurse, None, &f as *const Value as *const ());
+        f = pin(e, native);
+
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::CallBudgetExhausted as u32);
+        assert_eq!(last_error::kind(), ErrorKind::CallBudgetExhausted);
+        assert!(hexagon_ort_executor_impl_get_calls_used(e) > 100);
+        last_error::clear();
+
+        hexagon_ort_executor_impl_set_call_budget(e, 0);
+        f = pin(e, hexagon_ort_function_load_native_ex(return_null, None, null()));
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::Ok as u32);
+    });
+}
+
+/// Charges the calls it stands for, failing like a well-behaved native
+/// function if that goes over the budget.
+extern "C" fn charge_then_fail(
+    _: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    _: *const ()
+) -> i32 {
+    hexagon_ort_executor_impl_charge_calls(e, 1000)
+}
+
+#[test]
+fn failing_after_a_charge_reports_the_exhausted_budget() {
+    with_executor(|e| {
+        hexagon_ort_executor_impl_set_call_budget(e, 100);
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(charge_then_fail, None, null()));
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::CallBudgetExhausted as u32);
+        assert_eq!(last_error::kind(), ErrorKind::CallBudgetExhausted);
+        last_error::clear();
+
+        hexagon_ort_executor_impl_set_call_budget(e, 0);
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::Ok as u32);
+    });
+}
+
+fn expect_status(v: &Value, status: InvokeStatus) {
+    let mut n = 0;
+    assert_eq!(hexagon_ort_value_read_i64(&mut n, v), 0);
+    assert_eq!(n, status as i64);
+}
+
+struct Interrupter {
+    handle: *mut InterruptHandle,
+    target: Value
+}
+
+/// Triggers the handle in the `Interrupter` passed as `user_data` from
+/// another thread, then invokes its target and returns the status.
+extern "C" fn interrupt_then_invoke(
+    ret_place: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    let i = unsafe { &*(user_data as *const Interrupter) };
+    let sent = unsafe { (*i.handle).clone() };
+    ::std::thread::spawn(move || sent.trigger()).join().unwrap();
+
+    let status = invoke(e, &i.target, &[]);
+    unsafe { *ret_place = Value::Int(status as i64); }
+    0
+}
+
+#[test]
+fn interrupts_stop_the_running_invocation_only() {
+    with_executor(|e| {
+        let done = pin(e, hexagon_ort_function_load_native_ex(return_null, None, null()));
+        let h = hexagon_ort_executor_create_interrupt_handle(e);
+        assert!(!h.is_null());
+
+        let interrupter = Interrupter { handle: h, target: done };
+        let f = hexagon_ort_function_load_native_ex(interrupt_then_invoke, None, &interrupter as *const Interrupter as *const ());
+        let f = pin(e, f);
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        expect_status(&ret, InvokeStatus::Interrupted);
+        last_error::clear();
+
+        // A trigger sent between invocations is stale by the time the
+        // next one starts.
+        hexagon_ort_interrupt_handle_trigger(unsafe { &*h });
+        assert_eq!(invoke(e, &done, &[]), InvokeStatus::Ok as u32);
+        unsafe { hexagon_ort_interrupt_handle_destroy(h); }
+    });
+}
+
+struct Nested {
+    target: Value,
+    timeout_ms: u32,
+    sleep_ms: u64
+}
+
+/// Sleeps, then invokes the target in the `Nested` passed as `user_data`
+/// with its timeout. Returns what the target returned, or the status if
+/// it failed.
+extern "C" fn sleep_then_invoke(
+    ret_place: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    let n = unsafe { &*(user_data as *const Nested) };
+    ::std::thread::sleep(::std::time::Duration::from_millis(n.sleep_ms));
+    let status = hexagon_ort_executor_impl_invoke_with_timeout(
+        ret_place, null_mut(), e, &n.target, null(), null(), 0, n.timeout_ms
+    );
+    if status != InvokeStatus::Ok as u32 {
+        unsafe { *ret_place = Value::Int(status as i64); }
+    }
+    0
+}
+
+#[test]
+fn nested_invocations_cannot_extend_the_deadline() {
+    with_executor(|e| {
+        let done = pin(e, hexagon_ort_function_load_native_ex(return_null, None, null()));
+        let inner = Nested { target: done, timeout_ms: 10000, sleep_ms: 400 };
+        let f = hexagon_ort_function_load_native_ex(sleep_then_invoke, None, &inner as *const Nested as *const ());
+        let f = pin(e, f);
+
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_with_timeout(&mut ret, null_mut(), e, &f, null(), null(), 0, 200);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        expect_status(&ret, InvokeStatus::Timeout);
+        last_error::clear();
+    });
+}
+
+#[test]
+fn nested_invocations_can_shorten_the_deadline() {
+    with_executor(|e| {
+        let done = pin(e, hexagon_ort_function_load_native_ex(return_null, None, null()));
+        let sleeper = Nested { target: done, timeout_ms: 10000, sleep_ms: 400 };
+        let f = hexagon_ort_function_load_native_ex(sleep_then_invoke, None, &sleeper as *const Nested as *const ());
+        let outer = Nested { target: pin(e, f), timeout_ms: 200, sleep_ms: 0 };
+        let f = hexagon_ort_function_load_native_ex(sleep_then_invoke, None, &outer as *const Nested as *const ());
+        let f = pin(e, f);
+
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_with_timeout(&mut ret, null_mut(), e, &f, null(), null(), 0, 10000);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        expect_status(&ret, InvokeStatus::Timeout);
+        last_error::clear();
+
+        // The outer deadline is back once the nested invocation returns.
+        let status = hexagon_ort_executor_impl_invoke_with_timeout(&mut ret, null_mut(), e, &done, null(), null(), 0, 10000);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+    });
+}
+
+/// Fails with a message allocated with `hexagon_glue_alloc`, or succeeds
+/// and still leaves one behind if `user_data` is not null.
+extern "C" fn fail_with_message(
+    _: *mut Value,
+    err_place: *mut *mut c_char,
+    _: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    let msg = b"bad input\0";
+    unsafe {
+        let m = hexagon_glue_alloc(msg.len());
+        ::std::ptr::copy_nonoverlapping(msg.as_ptr(), m, msg.len());
+        *err_place = m as *mut c_char;
+    }
+    if user_data.is_null() { 1 } else { 0 }
+}
+
+#[test]
+fn native_error_messages_are_taken_from_err_place() {
+    with_executor(|e| {
+        let f = pin(e, hexagon_ort_function_load_native_ex(fail_with_message, None, null()));
+        let mut ret = Value::Null;
+        let mut err: *mut c_char = null_mut();
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::NativeError as u32);
+        assert!(!err.is_null());
+        assert!(unsafe { CStr::from_ptr(err) }.to_str().unwrap().contains("bad input"));
+        unsafe { hexagon_glue_destroy_cstring(err); }
+        last_error::clear();
+
+        // A message left behind on success is released and ignored.
+        let f = hexagon_ort_function_load_native_ex(fail_with_message, None, &ret as *const Value as *const ());
+        let f = pin(e, f);
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::Ok as u32);
+    });
+}
+
+/// Invokes the function in `user_data` and returns the value it threw.
+extern "C" fn invoke_then_get_thrown(
+    ret_place: *mut Value,
+    _: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    _: *const Value,
+    _: *const Value,
+    _: u32,
+    user_data: *const ()
+) -> i32 {
+    let target = unsafe { &*(user_data as *const Value) };
+    assert_eq!(invoke(e, target, &[]), InvokeStatus::NativeError as u32);
+    hexagon_ort_executor_impl_get_thrown_value(ret_place, e)
+}
+
+#[test]
+fn thrown_values_reach_the_caller() {
+    with_executor(|e| {
+        let thrower = pin(e, hexagon_ort_function_load_native_ex(throw_int, None, null()));
+        let f = hexagon_ort_function_load_native_ex(invoke_then_get_thrown, None, &thrower as *const Value as *const ());
+        let f = pin(e, f);
+
+        let mut ret = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+        let mut n = 0;
+        assert_eq!(hexagon_ort_value_read_i64(&mut n, &ret), 0);
+        assert_eq!(n, 42);
+        last_error::clear();
+    });
+}
+
+extern "C" fn throw_int_then_succeed(
+    ret_place: *mut Value,
+    err_place: *mut *mut c_char,
+    e: &mut ExecutorImpl,
+    this: *const Value,
+    args: *const Value,
+    n_args: u32,
+    user_data: *const ()
+) -> i32 {
+    throw_int(ret_place, err_place, e, this, args, n_args, user_data);
+    0
+}
+
+#[test]
+fn thrown_values_do_not_outlive_a_successful_callback() {
+    with_executor(|e| {
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_int_then_succeed, None, null()));
+        assert_eq!(invoke(e, &f, &[]), InvokeStatus::Ok as u32);
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(return_null, None, null()));
+        let mut ret = Value::Null;
+        let mut err = Value::Null;
+        let status = hexagon_ort_executor_impl_invoke_catch(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::Ok as u32);
+
+        let f = pin(e, hexagon_ort_function_load_native_ex(throw_message, None, null()));
+        let status = hexagon_ort_executor_impl_invoke_catch(&mut ret, &mut err, e, &f, null(), null(), 0);
+        assert_eq!(status, InvokeStatus::NativeError as u32);
+        assert_eq!(hexagon_ort_value_get_type_ex(&err, e), b'S');
+        assert_eq!(hexagon_ort_executor_impl_get_thrown_value(&mut ret, e), 1);
+        last_error::clear();
+    });
+}
@@ -0,0 +1,313 @@
+use std::os::raw::c_char;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::ptr::null;
+use hexagon_vm_core::errors::VMError;
+use super::handles::Handle;
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    None = 0,
+    InvalidArgument = 1,
+    InvalidUtf8 = 2,
+    TypeMismatch = 3,
+    Decode = 4,
+    Verification = 5,
+    VMError = 6,
+    Panic = 7,
+    Unsupported = 8,
+    NativeError = 9,
+    Encode = 10,
+    OutOfMemory = 11,
+    CallBudgetExhausted = 12,
+    Interrupted = 13,
+    Timeout = 14
+}
+
+impl ErrorKind {
+    /// Whether this error is raised by the bridge on its own to stop a
+    /// script, and should be reported as-is rather than as a native error.
+    pub fn is_limit(&self) -> bool {
+        match *self {
+            ErrorKind::OutOfMemory
+                | ErrorKind::CallBudgetExhausted
+                | ErrorKind::Interrupted
+                | ErrorKind::Timeout => true,
+            _ => false
+        }
+    }
+}
+
+/// C view of the last error recorded on the current thread.
+///
+/// `message` and `file` point into thread-local storage and are only valid
+/// until the next error is recorded or `hexagon_ort_clear_last_error` is called.
+#[repr(C)]
+pub struct LastErrorInfo {
+    pub kind: u32,
+    pub message: *const c_char,
+    pub file: *const c_char,
+    pub line: u32
+}
+
+struct LastError {
+    kind: ErrorKind,
+    message: CString,
+    file: CString,
+    line: u32
+}
+
+/// An error raised by the bridge that is still unwinding.
+struct Raised {
+    kind: ErrorKind,
+    /// For `RAISED`, the payload as `describe_panic` reports it once
+    /// caught, so that it can be told apart from any other error.
+    message: String,
+    value: Option<Handle>
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<LastError>> = RefCell::new(None);
+    static THROWN: RefCell<Option<(String, Option<Handle>)>> = RefCell::new(None);
+    static RAISED: RefCell<Option<Raised>> = RefCell::new(None);
+    static LIMIT: RefCell<Option<Raised>> = RefCell::new(None);
+}
+
+/// Records an error for the current thread, replacing the previous one.
+///
+/// Use the `set_last_error!` macro instead so that the source location
+/// is filled in automatically.
+pub fn set(kind: ErrorKind, message: String, file: &'static str, line: u32) {
+    let err = LastError {
+        kind: kind,
+        message: to_cstring(message),
+        file: to_cstring(file.to_string()),
+        line: line
+    };
+    LAST_ERROR.with(|v| *v.borrow_mut() = Some(err));
+}
+
+pub fn clear() {
+    LAST_ERROR.with(|v| *v.borrow_mut() = None);
+    THROWN.with(|v| *v.borrow_mut() = None);
+}
+
+/// Records the message a native callback wants to fail with. It is used
+/// instead of the generic message the next time a native error is raised
+/// on this thread.
+pub fn set_thrown(message: String) {
+    set_thrown_value(message, None);
+}
+
+/// Same as `set_thrown`, also recording the value thrown. The raised error
+/// carries it back to the invoking host.
+pub fn set_thrown_value(message: String, value: Option<Handle>) {
+    THROWN.with(|v| *v.borrow_mut() = Some((message, value)));
+}
+
+/// Forgets what a callback threw, if anything, and returns its message.
+/// Called when the callback returns, so that nothing stale is left behind.
+pub fn take_thrown() -> Option<String> {
+    take_thrown_value().map(|(message, _)| message)
+}
+
+pub fn take_thrown_value() -> Option<(String, Option<Handle>)> {
+    THROWN.with(|v| v.borrow_mut().take())
+}
+
+/// Records `message` as the last error and unwinds with it as a `VMError`.
+///
+/// The kind is remembered along with the message, so that the invocation
+/// catching the unwind can report it even if the last error has been
+/// replaced in between. Use the `raise!` macro instead so that the source
+/// location is filled in automatically.
+pub fn raise(kind: ErrorKind, message: String, file: &'static str, line: u32) -> ! {
+    raise_value(kind, message, None, file, line)
+}
+
+/// Same as `raise`, with the value thrown by a native callback.
+pub fn raise_value(
+    kind: ErrorKind,
+    message: String,
+    value: Option<Handle>,
+    file: &'static str,
+    line: u32
+) -> ! {
+    if kind.is_limit() {
+        hit_limit(kind, message.clone(), file, line);
+    } else {
+        set(kind, message.clone(), file, line);
+    }
+    RAISED.with(|v| *v.borrow_mut() = Some(Raised {
+        kind: kind,
+        message: VMError::from(message.as_str()).unwrap().to_string(),
+        value: value
+    }));
+    panic!(VMError::from(message.as_str()))
+}
+
+/// Records the limit of kind `kind` as hit without unwinding, for code
+/// that reports it through a status instead. A native callback failing
+/// afterwards is reported with this limit, as if it had been raised.
+///
+/// Use the `hit_limit!` macro instead so that the source location is
+/// filled in automatically.
+pub fn hit_limit(kind: ErrorKind, message: String, file: &'static str, line: u32) {
+    set(kind, message.clone(), file, line);
+    LIMIT.with(|v| *v.borrow_mut() = Some(Raised {
+        kind: kind,
+        message: message,
+        value: None
+    }));
+}
+
+/// Returns the kind passed to `raise` if `message`, caught from an unwind,
+/// is exactly the payload it raised. Errors raised by the VM itself yield
+/// `None`.
+pub fn take_raised(message: &str) -> Option<ErrorKind> {
+    take_raised_value(message).map(|(kind, _)| kind)
+}
+
+/// Same as `take_raised`, also returning the value thrown, if any.
+pub fn take_raised_value(message: &str) -> Option<(ErrorKind, Option<Handle>)> {
+    match RAISED.with(|v| v.borrow_mut().take()) {
+        Some(r) => if message == r.message {
+            Some((r.kind, r.value))
+        } else {
+            None
+        },
+        None => None
+    }
+}
+
+/// Returns the limit that stopped the current invocation, if any.
+///
+/// A native callback that fails after one of its own invocations hit a
+/// limit is reported with that limit rather than as a native error.
+pub fn limit_hit() -> Option<(ErrorKind, String)> {
+    LIMIT.with(|v| v.borrow().as_ref().map(|r| (r.kind, r.message.clone())))
+}
+
+/// Forgets errors still in flight from a previous invocation. Called when
+/// the host starts a new one; the last error itself is kept.
+pub fn begin_invocation() {
+    RAISED.with(|v| *v.borrow_mut() = None);
+    LIMIT.with(|v| *v.borrow_mut() = None);
+    THROWN.with(|v| *v.borrow_mut() = None);
+}
+
+pub fn kind() -> ErrorKind {
+    LAST_ERROR.with(|v| match *v.borrow() {
+        Some(ref e) => e.kind,
+        None => ErrorKind::None
+    })
+}
+
+/// Returns a copy of the last error recorded on the current thread.
+pub fn current() -> Option<(ErrorKind, String)> {
+    LAST_ERROR.with(|v| v.borrow().as_ref().map(|e| {
+        (e.kind, e.message.to_string_lossy().into_owned())
+    }))
+}
+
+pub fn get(ret_place: &mut LastErrorInfo) -> bool {
+    LAST_ERROR.with(|v| match *v.borrow() {
+        Some(ref e) => {
+            *ret_place = LastErrorInfo {
+                kind: e.kind as u32,
+                message: e.message.as_ptr(),
+                file: e.file.as_ptr(),
+                line: e.line
+            };
+            true
+        },
+        None => {
+            *ret_place = LastErrorInfo {
+                kind: ErrorKind::None as u32,
+                message: null(),
+                file: null(),
+                line: 0
+            };
+            false
+        }
+    })
+}
+
+/// Classifies a payload caught by `catch_unwind`.
+pub fn describe_panic(e: Box<Any + Send>) -> (ErrorKind, String) {
+    let e = match e.downcast::<VMError>() {
+        Ok(v) => return (ErrorKind::VMError, v.unwrap().to_string()),
+        Err(e) => e
+    };
+    let e = match e.downcast::<String>() {
+        Ok(v) => return (ErrorKind::Panic, *v),
+        Err(e) => e
+    };
+    match e.downcast::<&'static str>() {
+        Ok(v) => (ErrorKind::Panic, v.to_string()),
+        Err(_) => (ErrorKind::Panic, "Unknown error".to_string())
+    }
+}
+
+fn to_cstring(s: String) -> CString {
+    match CString::new(s) {
+        Ok(v) => v,
+        Err(e) => {
+            let mut s = e.into_vec();
+            s.retain(|&c| c != 0);
+            CString::new(s).unwrap()
+        }
+    }
+}
+
+macro_rules! set_last_error {
+    ($kind:expr, $($arg:tt)*) => {
+        $crate::ort::last_error::set($kind, format!($($arg)*), file!(), line!())
+    }
+}
+
+macro_rules! set_last_error_from_panic {
+    ($payload:expr, $context:expr) => {{
+        let (kind, msg) = $crate::ort::last_error::describe_panic($payload);
+        $crate::ort::last_error::set(kind, format!("{}: {}", $context, msg), file!(), line!());
+        kind
+    }}
+}
+
+macro_rules! hit_limit {
+    ($kind:expr, $($arg:tt)*) => {
+        $crate::ort::last_error::hit_limit($kind, format!($($arg)*), file!(), line!())
+    }
+}
+
+macro_rules! raise {
+    ($kind:expr, $($arg:tt)*) => {
+        $crate::ort::last_error::raise($kind, format!($($arg)*), file!(), line!())
+    }
+}
+
+/// Raises a `VMError` on behalf of a failing native callback, tagged as
+/// `NativeError` so that invokers can tell it apart.
+///
+/// A message thrown by the callback replaces `$msg`. If the callback
+/// failed because the bridge hit a limit, that error is raised instead.
+macro_rules! raise_native_error {
+    ($msg:expr) => {{
+        let (msg, value) = match $crate::ort::last_error::take_thrown_value() {
+            Some(v) => v,
+            None => ($msg.to_string(), None)
+        };
+        match $crate::ort::last_error::limit_hit() {
+            Some((kind, limit_msg)) => raise!(kind, "{}", limit_msg),
+            None => $crate::ort::last_error::raise_value(
+                $crate::ort::last_error::ErrorKind::NativeError,
+                msg,
+                value,
+                file!(),
+                line!()
+            )
+        }
+    }}
+}
@@ -0,0 +1,356 @@
+use std::os::raw::c_char;
+use std::ffi::CStr;
+use std::rc::Rc;
+use std::hash::{Hash, Hasher};
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::function::Function;
+use hexagon_vm_core::value::{Value, ValueContext};
+use hexagon_vm_core::errors::VMError;
+use super::object_proxy::ObjectProxy;
+use super::api::{NativeFunctionEx, call_native_ex};
+use super::handles::{Handle, HandleTable};
+use super::last_error::ErrorKind;
+use super::exec_state;
+
+/// Makes the method a const field of the proxy.
+pub const METHOD_CONST: u32 = 1;
+
+/// Describes a method shared by every proxy of a host class.
+///
+/// `cb` receives the proxy as `this` and its data as `user_data`.
+/// Calls with a number of arguments other than `arity` are rejected,
+/// unless `arity` is negative.
+#[repr(C)]
+pub struct MethodDef {
+    pub name: *const c_char,
+    pub cb: NativeFunctionEx,
+    pub arity: i32,
+    pub flags: u32
+}
+
+/// A `MethodDef` copied out of host memory. Proxies set up from equal
+/// specs share one `MethodTable`.
+#[derive(Clone)]
+pub struct MethodSpec {
+    name: String,
+    cb: NativeFunctionEx,
+    arity: i32,
+    flags: u32
+}
+
+impl MethodSpec {
+    fn key(&self) -> (&str, usize, i32, u32) {
+        (&self.name, self.cb as usize, self.arity, self.flags)
+    }
+}
+
+impl PartialEq for MethodSpec {
+    fn eq(&self, other: &MethodSpec) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for MethodSpec {}
+
+impl Hash for MethodSpec {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+struct Method {
+    name: String,
+    flags: u32,
+    handle: Handle
+}
+
+/// The method objects built for one list of specs. Tables are kept in
+/// the executor state until the executor goes away, and each method
+/// object is rooted by its table.
+pub struct MethodTable {
+    id: usize,
+    methods: Vec<Method>
+}
+
+/// Copies `defs`, which is only read here.
+///
+/// Returns `None` and records the reason in the last error if a
+/// definition is malformed.
+pub fn read_defs(defs: &[MethodDef]) -> Option<Vec<MethodSpec>> {
+    let mut specs = Vec::with_capacity(defs.len());
+    for def in defs {
+        if def.name.is_null() {
+            set_last_error!(ErrorKind::InvalidArgument, "Unexpected null method name");
+            return None;
+        }
+        let name = match unsafe { CStr::from_ptr(def.name) }.to_str() {
+            Ok(v) => v.to_string(),
+            Err(err) => {
+                set_last_error!(ErrorKind::InvalidUtf8, "Invalid UTF-8 string: {}", err);
+                return None;
+            }
+        };
+        specs.push(MethodSpec {
+            name: name,
+            cb: def.cb,
+            arity: def.arity,
+            flags: def.flags
+        });
+    }
+    Some(specs)
+}
+
+/// Returns the table of `e` built for `specs`, building it on first use.
+///
+/// Returns `None` and records the reason in the last error if the
+/// executor is out of memory.
+fn method_table(e: &mut ExecutorImpl, specs: &[MethodSpec]) -> Option<Rc<MethodTable>> {
+    if let Some(table) = exec_state::with(e, |s| s.method_tables.get(specs).cloned()) {
+        return Some(table);
+    }
+
+    let id = exec_state::with(e, |s| s.method_tables.len());
+    let mut methods = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let f = method_function(spec.name.clone(), spec.cb, spec.arity, id);
+        let f = match exec_state::allocate(e, Box::new(f)) {
+            Some(v) => v,
+            None => return None
+        };
+        methods.push(Method {
+            name: spec.name.clone(),
+            flags: spec.flags,
+            handle: HandleTable::root(e, Value::Object(f))
+        });
+    }
+
+    let table = Rc::new(MethodTable {
+        id: id,
+        methods: methods
+    });
+    exec_state::with_mut(e, |s| s.method_tables.insert(specs.to_vec(), table.clone()));
+    Some(table)
+}
+
+/// Adds the methods set on `p` as static fields. Called when a proxy is
+/// pinned to `e`.
+///
+/// Returns false and records the reason in the last error on failure.
+pub fn install(e: &mut ExecutorImpl, p: &mut ObjectProxy) -> bool {
+    let specs = match p.methods.take() {
+        Some(v) => v,
+        None => return true
+    };
+    let table = match method_table(e, &specs) {
+        Some(v) => v,
+        None => return false
+    };
+
+    let mut fields = p.static_fields.borrow_mut();
+    for m in table.methods.iter() {
+        fields.insert(m.name.clone(), m.handle.get());
+        p.method_fields.insert(m.name.clone());
+        if m.flags & METHOD_CONST != 0 {
+            p.const_fields.insert(m.name.clone());
+        }
+    }
+    p.method_table = Some(table.id);
+    true
+}
+
+/// Builds a method of table `table`. It only accepts proxies built with
+/// that table as `this`, so that a method copied to another object cannot
+/// hand that object's data to a callback expecting a different type.
+fn method_function(name: String, cb: NativeFunctionEx, arity: i32, table: usize) -> Function {
+    Function::from_native(Box::new(move |e: &mut ExecutorImpl| {
+        let (this, args) = exec_state::enter_native(e);
+
+        if arity >= 0 && args.len() != arity as usize {
+            panic!(VMError::from(format!(
+                "{}: expected {} arguments, got {}",
+                name,
+                arity,
+                args.len()
+            ).as_str()));
+        }
+
+        let data = if this.is_object() {
+            let ctx = ValueContext::new(&this, e.get_object_pool());
+            let data = ctx.as_object_direct().as_any().downcast_ref::<ObjectProxy>()
+                .and_then(|p| if p.method_table == Some(table) { Some(p.data) } else { None });
+            data
+        } else {
+            None
+        };
+        let data = match data {
+            Some(v) => v,
+            None => raise!(ErrorKind::TypeMismatch, "{}: called on an incompatible object", name)
+        };
+
+        call_native_ex(e, cb, this, &args, data)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_char;
+    use std::ptr::{null, null_mut};
+    use hexagon_vm_core::executor::ExecutorImpl;
+    use hexagon_vm_core::value::{Value, ValueContext};
+    use ort::api::*;
+    use ort::last_error::{self, ErrorKind};
+    use ort::test_util::{c_str, expect_int, with_executor};
+    use super::{MethodDef, METHOD_CONST};
+
+    /// Returns the proxy data as an integer.
+    extern "C" fn data_as_int(
+        ret_place: *mut Value,
+        _: *mut *mut c_char,
+        _: &mut ExecutorImpl,
+        _: *const Value,
+        _: *const Value,
+        _: u32,
+        user_data: *const ()
+    ) -> i32 {
+        unsafe { *ret_place = Value::Int(user_data as i64); }
+        0
+    }
+
+    /// Same as `data_as_int`, negated, standing for another class.
+    extern "C" fn negated_data(
+        ret_place: *mut Value,
+        _: *mut *mut c_char,
+        _: &mut ExecutorImpl,
+        _: *const Value,
+        _: *const Value,
+        _: u32,
+        user_data: *const ()
+    ) -> i32 {
+        unsafe { *ret_place = Value::Int(-(user_data as i64)); }
+        0
+    }
+
+    fn defs(name: &'static [u8]) -> [MethodDef; 1] {
+        [MethodDef {
+            name: c_str(name),
+            cb: data_as_int,
+            arity: 0,
+            flags: METHOD_CONST
+        }]
+    }
+
+    fn get_field(e: &ExecutorImpl, this: Value, name: &str) -> Option<Value> {
+        let pool = e.get_object_pool();
+        ValueContext::new(&this, pool).as_object_direct().get_field(pool, name)
+    }
+
+    fn call(e: &mut ExecutorImpl, f: Value, this: Value) -> Option<Value> {
+        let mut ret = Value::Null;
+        match hexagon_ort_executor_impl_invoke_checked(&mut ret, null_mut(), e, &f, &this, null(), 0) {
+            0 => Some(ret),
+            _ => None
+        }
+    }
+
+    fn pin_with_methods(e: &mut ExecutorImpl, data: usize, defs: &[MethodDef]) -> Value {
+        let p = hexagon_ort_object_proxy_create(data as *const ());
+        assert_eq!(hexagon_ort_object_proxy_set_methods(unsafe { &mut *p }, defs.as_ptr(), defs.len() as u32), 0);
+        let mut v = Value::Null;
+        assert_eq!(hexagon_ort_executor_pin_object_proxy(&mut v, e, p), 0);
+        v
+    }
+
+    #[test]
+    fn instances_share_one_table() {
+        with_executor(|e| {
+            let a = pin_with_methods(e, 1, &defs(b"get\0"));
+            // Equal definitions at another address.
+            let b = pin_with_methods(e, 2, &defs(b"get\0"));
+            let ha = hexagon_ort_handle_create(e, &a);
+            let hb = hexagon_ort_handle_create(e, &b);
+            assert_eq!(hexagon_ort_executor_impl_gc(e), 0);
+
+            let fa = get_field(e, a, "get").unwrap();
+            let fb = get_field(e, b, "get").unwrap();
+            assert_eq!(fa.as_object_id(), fb.as_object_id());
+            expect_int(call(e, fa, a), 1);
+            expect_int(call(e, fa, b), 2);
+
+            unsafe {
+                hexagon_ort_handle_destroy(ha);
+                hexagon_ort_handle_destroy(hb);
+            }
+        });
+    }
+
+    #[test]
+    fn changed_definitions_get_their_own_table() {
+        with_executor(|e| {
+            let mut defs = defs(b"first\0");
+            let first = pin_with_methods(e, 3, &defs);
+            defs[0].name = c_str(b"second\0");
+            let second = pin_with_methods(e, 3, &defs);
+
+            assert!(get_field(e, first, "first").is_some());
+            assert!(get_field(e, second, "second").is_some());
+            assert!(get_field(e, second, "first").is_none());
+        });
+    }
+
+    #[test]
+    fn methods_reject_objects_from_other_tables() {
+        with_executor(|e| {
+            let a = pin_with_methods(e, 4, &defs(b"get\0"));
+            let mut other = defs(b"get\0");
+            other[0].cb = negated_data;
+            let b = pin_with_methods(e, 5, &other);
+            let plain = pin_with_methods(e, 6, &[]);
+
+            let fa = get_field(e, a, "get").unwrap();
+            let fb = get_field(e, b, "get").unwrap();
+            expect_int(call(e, fb, b), -5);
+            assert!(call(e, fa, b).is_none());
+            assert_eq!(last_error::kind(), ErrorKind::TypeMismatch);
+            assert!(call(e, fa, plain).is_none());
+            assert!(call(e, fa, Value::Int(1)).is_none());
+            last_error::clear();
+        });
+    }
+
+    extern "C" fn collect_name(name: *const u8, len: u32, user_data: *const ()) -> i32 {
+        let names = unsafe { &mut *(user_data as *mut Vec<String>) };
+        let name = unsafe { ::std::slice::from_raw_parts(name, len as usize) };
+        names.push(String::from_utf8(name.to_vec()).unwrap());
+        0
+    }
+
+    #[test]
+    fn methods_are_not_enumerated() {
+        with_executor(|e| {
+            let v = pin_with_methods(e, 7, &defs(b"get\0"));
+            let mut names: Vec<String> = Vec::new();
+            assert_eq!(hexagon_ort_object_proxy_enumerate_fields(&v, e, collect_name, &mut names as *mut Vec<String> as *const ()), 0);
+            assert!(names.is_empty());
+        });
+    }
+
+    #[test]
+    fn malformed_definitions_are_rejected() {
+        let p = hexagon_ort_object_proxy_create(null());
+        let p = unsafe { &mut *p };
+        assert_eq!(hexagon_ort_object_proxy_set_methods(p, null(), 1), 1);
+        assert_eq!(last_error::kind(), ErrorKind::InvalidArgument);
+
+        let mut defs = defs(b"x\0");
+        defs[0].name = null();
+        assert_eq!(hexagon_ort_object_proxy_set_methods(p, defs.as_ptr(), 1), 1);
+        assert_eq!(last_error::kind(), ErrorKind::InvalidArgument);
+
+        defs[0].name = c_str(b"\xff\0");
+        assert_eq!(hexagon_ort_object_proxy_set_methods(p, defs.as_ptr(), 1), 1);
+        assert_eq!(last_error::kind(), ErrorKind::InvalidUtf8);
+        last_error::clear();
+
+        unsafe { hexagon_ort_object_proxy_destroy(p); }
+    }
+}
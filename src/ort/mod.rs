@@ -1,5 +1,25 @@
+#[macro_use]
+pub mod last_error;
+
 pub mod api;
 pub mod object_proxy;
+pub mod bytes;
+pub mod collections;
+pub mod serialize;
+pub mod handles;
+pub mod exec_state;
+pub mod interrupt;
+pub mod signature;
+pub mod methods;
+
+#[cfg(test)]
+pub(crate) mod test_util;
 
 #[cfg(test)]
 mod print_layout;
+
+#[cfg(test)]
+mod invalid_input;
+
+#[cfg(test)]
+mod invocation;
@@ -1,29 +1,82 @@
 use std::os::raw::c_char;
 use std::any::Any;
-use std::ffi::CString;
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use smallvec::SmallVec;
 use hexagon_vm_core::executor::ExecutorImpl;
 use hexagon_vm_core::object::Object;
 use hexagon_vm_core::object_pool::ObjectPool;
-use hexagon_vm_core::value::Value;
+use hexagon_vm_core::value::{Value, ValueContext};
 use hexagon_vm_core::errors::VMError;
+use glue::hexagon_glue_free;
+use super::exec_state;
+use super::methods::MethodSpec;
+use super::last_error::{self, ErrorKind};
 
 pub type Destructor = extern "C" fn (data: *const ());
 pub type OnCall = extern "C" fn (ret_place: *mut Value, data: *const (), n_args: u32, args: *const Value) -> i32;
 pub type OnGetField = extern "C" fn (ret_place: *mut Value, data: *const (), field_name: *const c_char) -> i32;
 pub type OnSetField = extern "C" fn (data: *const (), field_name: *const c_char, value: *const Value) -> i32;
+
+/// The returned string is borrowed from the host and must stay valid
+/// and unchanged for as long as the proxy is alive.
 pub type OnTypename = extern "C" fn (data: *const ()) -> *const c_char;
 pub type OnToI64 = extern "C" fn (ret_place: *mut i64, data: *const ()) -> i32;
 pub type OnToF64 = extern "C" fn (ret_place: *mut f64, data: *const ()) -> i32;
+
+/// Same ownership rules as `OnTypename`: the string is borrowed and must
+/// outlive the proxy.
 pub type OnToStr = extern "C" fn (data: *const ()) -> *const c_char;
-pub type OnToString = extern "C" fn (data: *const ()) -> *const c_char;
+
+/// The returned string is owned by the bridge. It must be allocated with
+/// `hexagon_glue_alloc` and is released with `hexagon_glue_free` once copied.
+pub type OnToString = extern "C" fn (data: *const ()) -> *mut c_char;
+
 pub type OnToBool = extern "C" fn (ret_place: *mut u32, data: *const ()) -> i32;
 
+/// Compares the proxy with `other`, writing a negative number, zero or a
+/// positive number if the proxy is less than, equal to or greater than it.
+pub type OnCompare = extern "C" fn (ret_place: *mut i32, data: *const (), other: *const Value) -> i32;
+
+pub type OnHasField = extern "C" fn (ret_place: *mut u32, data: *const (), field_name: *const c_char) -> i32;
+pub type OnDeleteField = extern "C" fn (data: *const (), field_name: *const c_char) -> i32;
+
+/// Receives one field name with its length. Returning non-zero stops
+/// the enumeration.
+pub type FieldVisitor = extern "C" fn (name: *const u8, len: u32, visit_ctx: *const ()) -> i32;
+
+/// Calls `visit` with `visit_ctx` for each dynamic field of the proxy.
+pub type OnEnumerateFields = extern "C" fn (data: *const (), visit: FieldVisitor, visit_ctx: *const ()) -> i32;
+
+pub type TraceVisitor = extern "C" fn (visit_ctx: *const (), value: *const Value);
+
+/// Reports the values kept alive by the host data of the proxy, by calling
+/// `visit` with `visit_ctx` on each of them. Called during collection, so
+/// it must not touch the executor. Null pointers passed to `visit` are
+/// ignored.
+pub type OnTrace = extern "C" fn (data: *const (), visit: TraceVisitor, visit_ctx: *const ());
+
+/// Call handler for proxies built from Rust, which need the executor.
+/// Takes precedence over `on_call`.
+pub type HostCall = Box<Fn(&mut ExecutorImpl) -> Value>;
+
+/// Field reader for proxies built from Rust, which can tell a missing
+/// field apart from a null one. Takes precedence over `on_get_field`.
+pub type HostGetField = Box<Fn(&str) -> Option<Value>>;
+
 pub struct ObjectProxy {
     pub(crate) frozen: bool,
     pub(crate) const_fields: HashSet<String>,
+    /// Static fields installed from `methods`, which are left out of
+    /// enumeration.
+    pub(crate) method_fields: HashSet<String>,
     pub(crate) data: *const (),
+    /// Methods to install when the proxy is pinned.
+    pub(crate) methods: Option<Vec<MethodSpec>>,
+    /// The table the methods of the proxy were taken from, once pinned.
+    pub(crate) method_table: Option<usize>,
     pub(crate) destructor: Option<Destructor>,
     pub(crate) on_call: Option<OnCall>,
     pub(crate) on_get_field: Option<OnGetField>,
@@ -34,7 +87,14 @@ pub struct ObjectProxy {
     pub(crate) on_to_str: Option<OnToStr>,
     pub(crate) on_to_string: Option<OnToString>,
     pub(crate) on_to_bool: Option<OnToBool>,
-    pub(crate) static_fields: HashMap<String, Value>
+    pub(crate) host_call: Option<HostCall>,
+    pub(crate) host_get_field: Option<HostGetField>,
+    pub(crate) on_compare: Option<OnCompare>,
+    pub(crate) on_has_field: Option<OnHasField>,
+    pub(crate) on_delete_field: Option<OnDeleteField>,
+    pub(crate) on_enumerate_fields: Option<OnEnumerateFields>,
+    pub(crate) on_trace: Option<OnTrace>,
+    pub(crate) static_fields: RefCell<HashMap<String, Value>>
 }
 
 impl ObjectProxy {
@@ -42,7 +102,10 @@ impl ObjectProxy {
         ObjectProxy {
             frozen: false,
             const_fields: HashSet::new(),
+            method_fields: HashSet::new(),
             data: data,
+            methods: None,
+            method_table: None,
             destructor: None,
             on_call: None,
             on_get_field: None,
@@ -53,7 +116,115 @@ impl ObjectProxy {
             on_to_str: None,
             on_to_string: None,
             on_to_bool: None,
-            static_fields: HashMap::new()
+            host_call: None,
+            host_get_field: None,
+            on_compare: None,
+            on_has_field: None,
+            on_delete_field: None,
+            on_enumerate_fields: None,
+            on_trace: None,
+            static_fields: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub(crate) fn has_field(&self, name: &str) -> bool {
+        if self.static_fields.borrow().contains_key(name) {
+            return true;
+        }
+
+        if let Some(f) = self.on_has_field {
+            let mut ret_place: u32 = 0;
+            let name = to_c_name(name);
+            ensure_proxied_ok((f)(&mut ret_place, self.data, &name[0] as *const u8 as *const c_char));
+            ret_place != 0
+        } else {
+            false
+        }
+    }
+
+    /// Removes a field. Static fields are removed directly; other fields
+    /// go through `on_delete_field`.
+    pub(crate) fn delete_field(&self, name: &str) {
+        if self.frozen || self.const_fields.contains(name) {
+            panic!(VMError::from("Cannot delete const field"));
+        }
+
+        if self.static_fields.borrow_mut().remove(name).is_some() {
+            return;
+        }
+
+        if let Some(f) = self.on_delete_field {
+            let name = to_c_name(name);
+            ensure_proxied_ok((f)(self.data, &name[0] as *const u8 as *const c_char));
+        }
+    }
+
+    /// Names of the static fields merged with the ones reported by
+    /// `on_enumerate_fields`, in sorted order and without duplicates.
+    /// Methods installed by the bridge are left out.
+    ///
+    /// Raises an `InvalidUtf8` error if the hook reports a malformed name.
+    pub(crate) fn field_names(&self) -> Vec<String> {
+        let mut names: BTreeSet<String> = self.static_fields.borrow().keys()
+            .filter(|k| !self.method_fields.contains(*k))
+            .cloned()
+            .collect();
+
+        if let Some(f) = self.on_enumerate_fields {
+            let mut dynamic = FieldNames {
+                names: Vec::new(),
+                invalid: false
+            };
+            let err = (f)(
+                self.data,
+                collect_field_name,
+                &mut dynamic as *mut FieldNames as *const ()
+            );
+            if dynamic.invalid {
+                raise!(ErrorKind::InvalidUtf8, "Invalid UTF-8 in field name of {}", self.typename());
+            }
+            ensure_proxied_ok(err);
+            names.extend(dynamic.names);
+        }
+
+        names.into_iter().collect()
+    }
+}
+
+extern "C" fn collect_child(visit_ctx: *const (), value: *const Value) {
+    if value.is_null() {
+        return;
+    }
+    let children = unsafe { &mut *(visit_ctx as *mut Vec<usize>) };
+    let value = unsafe { *value };
+    if value.is_object() {
+        children.push(value.as_object_id());
+    }
+}
+
+struct FieldNames {
+    names: Vec<String>,
+    invalid: bool
+}
+
+extern "C" fn collect_field_name(name: *const u8, len: u32, visit_ctx: *const ()) -> i32 {
+    let ctx = unsafe { &mut *(visit_ctx as *mut FieldNames) };
+    let name = if len == 0 {
+        &[]
+    } else if name.is_null() {
+        ctx.invalid = true;
+        return 1;
+    } else {
+        unsafe { ::std::slice::from_raw_parts(name, len as usize) }
+    };
+    match ::std::str::from_utf8(name) {
+        Ok(v) => {
+            ctx.names.push(v.to_string());
+            0
+        },
+        Err(_) => {
+            ctx.invalid = true;
+            1
         }
     }
 }
@@ -68,11 +239,17 @@ impl Drop for ObjectProxy {
 
 impl Object for ObjectProxy {
     fn get_children(&self) -> Vec<usize> {
-        self.static_fields.iter()
-            .map(|(k, v)| v)
+        let mut children: Vec<usize> = self.static_fields.borrow().iter()
+            .map(|(_, v)| v)
             .filter(|v| v.is_object())
             .map(|v| v.as_object_id())
-            .collect()
+            .collect();
+
+        if let Some(f) = self.on_trace {
+            (f)(self.data, collect_child, &mut children as *mut Vec<usize> as *const ());
+        }
+
+        children
     }
 
     fn as_any(&self) -> &Any {
@@ -84,12 +261,14 @@ impl Object for ObjectProxy {
     }
 
     fn call(&self, executor: &mut ExecutorImpl) -> Value {
-        if let Some(f) = self.on_call {
-            let mut ret_place = Value::Null;
+        if let Some(ref f) = self.host_call {
+            // `CallContext::from_frame` enters the call.
+            f(executor)
+        } else if let Some(f) = self.on_call {
+            let (_, args) = exec_state::enter_native(executor);
+            let n_args = args.len();
 
-            let frame = executor.get_current_frame();
-            let n_args = frame.get_n_arguments();
-            let args: SmallVec<[Value; 4]> = (0..n_args).map(|i| frame.get_argument(i).unwrap()).collect();
+            let mut ret_place = Value::Null;
 
             ensure_proxied_ok(
                 if n_args > 0 {
@@ -105,22 +284,51 @@ impl Object for ObjectProxy {
     }
 
     fn get_field(&self, _pool: &ObjectPool, name: &str) -> Option<Value> {
-        if let Some(v) = self.static_fields.get(name) {
+        if let Some(v) = self.static_fields.borrow().get(name) {
             return Some(*v);
         }
 
-        if let Some(f) = self.on_get_field {
+        if let Some(ref f) = self.host_get_field {
+            f(name)
+        } else if let Some(f) = self.on_get_field {
+            if self.on_has_field.is_some() && !self.has_field(name) {
+                return None;
+            }
+
             let mut ret_place = Value::Null;
 
-            let mut name: SmallVec<[u8; 32]> = name.as_bytes().into();
-            name.push(0);
+            let name = to_c_name(name);
 
             ensure_proxied_ok(
-                (f)(&mut ret_place, self.data, &name[0] as *const u8 as *const i8)
+                (f)(&mut ret_place, self.data, &name[0] as *const u8 as *const c_char)
             );
             Some(ret_place)
         } else {
-            panic!(VMError::from("Not implemented"));
+            None
+        }
+    }
+
+    /// Static fields shadow the host's fields for both reads and writes:
+    /// an existing static field is updated in place, and only other names
+    /// reach `on_set_field`.
+    fn set_field(&self, name: &str, value: Value) {
+        if self.frozen || self.const_fields.contains(name) {
+            panic!(VMError::from("Cannot set const field"));
+        }
+
+        if let Some(v) = self.static_fields.borrow_mut().get_mut(name) {
+            *v = value;
+            return;
+        }
+
+        if let Some(f) = self.on_set_field {
+            let name = to_c_name(name);
+
+            ensure_proxied_ok(
+                (f)(self.data, &name[0] as *const u8 as *const c_char, &value)
+            );
+        } else {
+            self.static_fields.borrow_mut().insert(name.to_string(), value);
         }
     }
 
@@ -133,10 +341,394 @@ impl Object for ObjectProxy {
             false
         }
     }
+
+    fn compare(&self, other: &ValueContext) -> Option<Ordering> {
+        if let Some(f) = self.on_compare {
+            let mut ret_place: i32 = 0;
+            ensure_proxied_ok((f)(&mut ret_place, self.data, other.value));
+            Some(to_ordering(ret_place))
+        } else {
+            raise!(ErrorKind::Unsupported, "Unsupported operand for comparison: {}", self.typename());
+        }
+    }
+
+    fn test_eq(&self, other: &ValueContext) -> bool {
+        if let Some(f) = self.on_compare {
+            let mut ret_place: i32 = 0;
+            ensure_proxied_ok((f)(&mut ret_place, self.data, other.value));
+            ret_place == 0
+        } else {
+            // Without a hook, a proxy is only equal to itself.
+            other.value.is_object()
+                && other.as_object_direct() as *const Object as *const () == self as *const ObjectProxy as *const ()
+        }
+    }
+
+    fn typename(&self) -> &str {
+        if let Some(f) = self.on_typename {
+            borrow_proxied_str((f)(self.data))
+        } else {
+            "object_proxy"
+        }
+    }
+
+    fn to_i64(&self) -> i64 {
+        if let Some(f) = self.on_to_i64 {
+            let mut ret_place: i64 = 0;
+            ensure_proxied_ok((f)(&mut ret_place, self.data));
+            ret_place
+        } else {
+            panic!(VMError::from("Cannot convert proxied object to i64"));
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        if let Some(f) = self.on_to_f64 {
+            let mut ret_place: f64 = 0.0;
+            ensure_proxied_ok((f)(&mut ret_place, self.data));
+            ret_place
+        } else {
+            panic!(VMError::from("Cannot convert proxied object to f64"));
+        }
+    }
+
+    fn to_str(&self) -> &str {
+        if let Some(f) = self.on_to_str {
+            borrow_proxied_str((f)(self.data))
+        } else {
+            panic!(VMError::from("Cannot convert proxied object to str"));
+        }
+    }
+
+    fn to_string(&self) -> String {
+        if let Some(f) = self.on_to_string {
+            take_proxied_string((f)(self.data))
+        } else {
+            self.to_str().to_string()
+        }
+    }
+
+    fn to_bool(&self) -> bool {
+        if let Some(f) = self.on_to_bool {
+            let mut ret_place: u32 = 0;
+            ensure_proxied_ok((f)(&mut ret_place, self.data));
+            ret_place != 0
+        } else {
+            panic!(VMError::from("Cannot convert proxied object to bool"));
+        }
+    }
+}
+
+fn borrow_proxied_str<'a>(s: *const c_char) -> &'a str {
+    if s.is_null() {
+        raise_native_error!("Proxied object returns null string");
+    }
+    match unsafe { CStr::from_ptr(s) }.to_str() {
+        Ok(v) => v,
+        Err(_) => raise_native_error!("Proxied object returns invalid UTF-8")
+    }
+}
+
+fn take_proxied_string(s: *mut c_char) -> String {
+    if s.is_null() {
+        raise_native_error!("Proxied object returns null string");
+    }
+    let ret = unsafe { CStr::from_ptr(s) }.to_str().map(|v| v.to_string());
+    unsafe { hexagon_glue_free(s as *mut u8); }
+
+    match ret {
+        Ok(v) => v,
+        Err(_) => raise_native_error!("Proxied object returns invalid UTF-8")
+    }
+}
+
+/// Maps the result of an `on_compare` hook to an ordering.
+fn to_ordering(v: i32) -> Ordering {
+    if v < 0 {
+        Ordering::Less
+    } else if v > 0 {
+        Ordering::Greater
+    } else {
+        Ordering::Equal
+    }
+}
+
+fn to_c_name(name: &str) -> SmallVec<[u8; 32]> {
+    let mut name: SmallVec<[u8; 32]> = name.as_bytes().into();
+    name.push(0);
+    name
 }
 
 fn ensure_proxied_ok(err: i32) {
     if err != 0 {
-        panic!(VMError::from("Proxied object returns error"));
+        raise_native_error!("Proxied object returns error");
+    }
+    last_error::take_thrown();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::c_char;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use std::ptr::null;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use hexagon_vm_core::executor::{Executor, ExecutorImpl};
+    use hexagon_vm_core::object::Object;
+    use hexagon_vm_core::value::{Value, ValueContext};
+    use glue::hexagon_glue_alloc;
+    use ort::api::*;
+    use ort::last_error::{self, ErrorKind};
+    use ort::test_util::{c_str, expect_int, pin_counted_proxy, with_executor};
+    use super::{FieldVisitor, ObjectProxy, TraceVisitor};
+
+    extern "C" fn reject_set(_: *const (), _: *const c_char, _: *const Value) -> i32 {
+        1
+    }
+
+    #[test]
+    fn static_fields_shadow_host_fields() {
+        with_executor(|e| {
+            let mut p = ObjectProxy::new(null());
+            p.on_set_field = Some(reject_set);
+            p.static_fields.borrow_mut().insert("x".to_string(), Value::Int(1));
+
+            p.set_field("x", Value::Int(2));
+            expect_int(p.get_field(e.get_object_pool(), "x"), 2);
+
+            assert!(catch_unwind(AssertUnwindSafe(|| p.set_field("y", Value::Int(3)))).is_err());
+        });
+    }
+
+    extern "C" fn borrowed_typename(_: *const ()) -> *const c_char {
+        b"point\0".as_ptr() as *const c_char
+    }
+
+    extern "C" fn owned_string(_: *const ()) -> *mut c_char {
+        let s = b"(1, 2)\0";
+        unsafe {
+            let buf = hexagon_glue_alloc(s.len());
+            ::std::ptr::copy_nonoverlapping(s.as_ptr(), buf, s.len());
+            buf as *mut c_char
+        }
+    }
+
+    extern "C" fn null_string(_: *const ()) -> *mut c_char {
+        ::std::ptr::null_mut()
+    }
+
+    #[test]
+    fn string_hooks_follow_ownership_rules() {
+        let mut p = ObjectProxy::new(null());
+        p.on_typename = Some(borrowed_typename);
+        p.on_to_string = Some(owned_string);
+
+        assert_eq!(p.typename(), "point");
+        assert_eq!(p.typename(), "point");
+        assert_eq!(p.to_string(), "(1, 2)");
+        assert_eq!(p.to_string(), "(1, 2)");
+
+        p.on_to_string = Some(null_string);
+        assert!(catch_unwind(AssertUnwindSafe(|| p.to_string())).is_err());
+        assert_eq!(last_error::kind(), ErrorKind::NativeError);
+        last_error::clear();
+    }
+
+    #[test]
+    fn const_and_frozen_fields_reject_writes() {
+        with_executor(|e| {
+            let mut p = ObjectProxy::new(null());
+            p.const_fields.insert("c".to_string());
+            p.static_fields.borrow_mut().insert("c".to_string(), Value::Int(1));
+
+            assert!(catch_unwind(AssertUnwindSafe(|| p.set_field("c", Value::Int(2)))).is_err());
+            expect_int(p.get_field(e.get_object_pool(), "c"), 1);
+            assert!(p.has_const_field(e.get_object_pool(), "c"));
+
+            p.set_field("d", Value::Int(3));
+            expect_int(p.get_field(e.get_object_pool(), "d"), 3);
+
+            p.frozen = true;
+            assert!(p.has_const_field(e.get_object_pool(), "d"));
+            assert!(catch_unwind(AssertUnwindSafe(|| p.set_field("d", Value::Int(4)))).is_err());
+            expect_int(p.get_field(e.get_object_pool(), "d"), 3);
+        });
+    }
+
+    static TRACED_DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    extern "C" fn trace_slot(data: *const (), visit: TraceVisitor, visit_ctx: *const ()) {
+        visit(visit_ctx, data as *const Value);
+    }
+
+    #[test]
+    fn values_traced_from_host_data_survive_collection() {
+        let mut executor = Executor::new();
+        let e = unsafe { &mut *hexagon_ort_executor_get_impl(&mut executor) };
+
+        let child_value = pin_counted_proxy(e, &TRACED_DROPS);
+
+        // The holder keeps the child only in its host data.
+        let slot = Box::into_raw(Box::new(child_value));
+        let holder = hexagon_ort_object_proxy_create(slot as *const ());
+        unsafe { hexagon_ort_object_proxy_set_on_trace(&mut *holder, Some(trace_slot)); }
+        let mut holder_value = Value::Null;
+        assert_eq!(hexagon_ort_executor_pin_object_proxy(&mut holder_value, e, holder), 0);
+
+        let h = hexagon_ort_handle_create(e, &holder_value);
+        e.gc(true);
+        assert_eq!(TRACED_DROPS.load(Ordering::SeqCst), 0);
+
+        unsafe { hexagon_ort_handle_destroy(h); }
+        e.gc(true);
+        assert_eq!(TRACED_DROPS.load(Ordering::SeqCst), 1);
+
+        unsafe { Box::from_raw(slot); }
+    }
+
+    /// Orders the proxy by its data against integers.
+    extern "C" fn compare_data(ret_place: *mut i32, data: *const (), other: *const Value) -> i32 {
+        match unsafe { *other } {
+            Value::Int(n) => {
+                unsafe { *ret_place = (data as i64 - n).signum() as i32; }
+                0
+            },
+            _ => 1
+        }
+    }
+
+    fn pin(e: &mut ExecutorImpl, p: ObjectProxy) -> Value {
+        let mut v = Value::Null;
+        assert_eq!(hexagon_ort_executor_pin_object_proxy(&mut v, e, Box::into_raw(Box::new(p))), 0);
+        v
+    }
+
+    #[test]
+    fn comparisons_reach_the_hook() {
+        with_executor(|e| {
+            let mut p = ObjectProxy::new(2 as *const ());
+            p.on_compare = Some(compare_data);
+            let v = pin(e, p);
+
+            let pool = e.get_object_pool();
+            let p = ValueContext::new(&v, pool).as_object_direct();
+            assert_eq!(p.compare(&ValueContext::new(&Value::Int(3), pool)), Some(::std::cmp::Ordering::Less));
+            assert!(p.test_eq(&ValueContext::new(&Value::Int(2), pool)));
+            assert!(!p.test_eq(&ValueContext::new(&Value::Int(1), pool)));
+            assert!(catch_unwind(AssertUnwindSafe(|| p.compare(&ValueContext::new(&Value::Null, pool)))).is_err());
+            assert_eq!(last_error::kind(), ErrorKind::NativeError);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn comparisons_without_a_hook() {
+        with_executor(|e| {
+            let v = pin(e, ObjectProxy::new(null()));
+            let other = pin(e, ObjectProxy::new(null()));
+
+            let pool = e.get_object_pool();
+            let p = ValueContext::new(&v, pool).as_object_direct();
+            assert!(p.test_eq(&ValueContext::new(&v, pool)));
+            assert!(!p.test_eq(&ValueContext::new(&other, pool)));
+            assert!(!p.test_eq(&ValueContext::new(&Value::Int(1), pool)));
+            assert!(catch_unwind(AssertUnwindSafe(|| p.compare(&ValueContext::new(&other, pool)))).is_err());
+            assert_eq!(last_error::kind(), ErrorKind::Unsupported);
+            last_error::clear();
+        });
+    }
+
+    extern "C" fn has_dyn(ret_place: *mut u32, _: *const (), name: *const c_char) -> i32 {
+        let name = unsafe { ::std::ffi::CStr::from_ptr(name) };
+        unsafe { *ret_place = if name.to_bytes() == b"dyn" { 1 } else { 0 }; }
+        0
+    }
+
+    static DELETED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    extern "C" fn delete_dyn(_: *const (), name: *const c_char) -> i32 {
+        let name = unsafe { ::std::ffi::CStr::from_ptr(name) };
+        if name.to_bytes() == b"dyn" {
+            DELETED.fetch_add(1, Ordering::SeqCst);
+            0
+        } else {
+            1
+        }
+    }
+
+    /// Reports `dyn`, then the name pointed to by `data` if there is one.
+    extern "C" fn enumerate_dyn(data: *const (), visit: FieldVisitor, visit_ctx: *const ()) -> i32 {
+        if visit(b"dyn".as_ptr(), 3, visit_ctx) != 0 {
+            return 1;
+        }
+        if !data.is_null() {
+            let name = unsafe { &*(data as *const &[u8]) };
+            if visit(name.as_ptr(), name.len() as u32, visit_ctx) != 0 {
+                return 1;
+            }
+        }
+        0
+    }
+
+    extern "C" fn collect_name(name: *const u8, len: u32, user_data: *const ()) -> i32 {
+        let names = unsafe { &mut *(user_data as *mut Vec<String>) };
+        let name = unsafe { ::std::slice::from_raw_parts(name, len as usize) };
+        names.push(String::from_utf8(name.to_vec()).unwrap());
+        0
+    }
+
+    fn dynamic_proxy(data: *const ()) -> ObjectProxy {
+        let mut p = ObjectProxy::new(data);
+        p.on_has_field = Some(has_dyn);
+        p.on_delete_field = Some(delete_dyn);
+        p.on_enumerate_fields = Some(enumerate_dyn);
+        p.static_fields.borrow_mut().insert("x".to_string(), Value::Int(1));
+        p
+    }
+
+    #[test]
+    fn has_and_delete_fields() {
+        with_executor(|e| {
+            let v = pin(e, dynamic_proxy(null()));
+            let mut has = 0;
+
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"x\0")), 0);
+            assert_eq!(has, 1);
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"dyn\0")), 0);
+            assert_eq!(has, 1);
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"y\0")), 0);
+            assert_eq!(has, 0);
+
+            assert_eq!(hexagon_ort_object_proxy_delete_field(&v, e, c_str(b"x\0")), 0);
+            assert_eq!(hexagon_ort_object_proxy_has_field(&mut has, &v, e, c_str(b"x\0")), 0);
+            assert_eq!(has, 0);
+            assert_eq!(hexagon_ort_object_proxy_delete_field(&v, e, c_str(b"dyn\0")), 0);
+            assert_eq!(DELETED.load(Ordering::SeqCst), 1);
+            assert_eq!(hexagon_ort_object_proxy_delete_field(&v, e, c_str(b"y\0")), 1);
+            assert_eq!(last_error::kind(), ErrorKind::NativeError);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn enumeration_merges_static_and_dynamic_fields() {
+        with_executor(|e| {
+            let v = pin(e, dynamic_proxy(null()));
+            let mut names: Vec<String> = Vec::new();
+            assert_eq!(hexagon_ort_object_proxy_enumerate_fields(&v, e, collect_name, &mut names as *mut Vec<String> as *const ()), 0);
+            assert_eq!(names, vec!["dyn".to_string(), "x".to_string()]);
+        });
+    }
+
+    #[test]
+    fn enumeration_rejects_malformed_names() {
+        with_executor(|e| {
+            let bad: &[u8] = b"\xff";
+            let v = pin(e, dynamic_proxy(&bad as *const &[u8] as *const ()));
+            let mut names: Vec<String> = Vec::new();
+            assert_eq!(hexagon_ort_object_proxy_enumerate_fields(&v, e, collect_name, &mut names as *mut Vec<String> as *const ()), 1);
+            assert_eq!(last_error::kind(), ErrorKind::InvalidUtf8);
+            assert!(names.is_empty());
+            last_error::clear();
+        });
     }
 }
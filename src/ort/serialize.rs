@@ -0,0 +1,363 @@
+use std::fmt;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use serde::ser::{self, Serialize, Serializer, SerializeSeq, SerializeMap};
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor, SeqAccess, MapAccess};
+use hexagon_vm_core::object_pool::ObjectPool;
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::object::Object;
+use hexagon_vm_core::value::{Value, ValueContext};
+use super::bytes::Bytes;
+use super::collections::{Array, Map};
+use super::exec_state;
+
+pub const DEFAULT_MAX_DEPTH: u32 = 128;
+
+/// Serializes a value graph rooted at `value`.
+///
+/// Strings, byte buffers, arrays and maps are supported. Other objects,
+/// cycles and graphs deeper than `max_depth` are rejected.
+pub struct SerializeValue<'a> {
+    value: Value,
+    pool: &'a ObjectPool,
+    depth_left: u32,
+    visiting: &'a RefCell<HashSet<usize>>
+}
+
+impl<'a> SerializeValue<'a> {
+    pub fn new(
+        value: Value,
+        pool: &'a ObjectPool,
+        max_depth: u32,
+        visiting: &'a RefCell<HashSet<usize>>
+    ) -> SerializeValue<'a> {
+        SerializeValue {
+            value: value,
+            pool: pool,
+            depth_left: max_depth,
+            visiting: visiting
+        }
+    }
+
+    fn child(&self, value: Value) -> SerializeValue<'a> {
+        SerializeValue {
+            value: value,
+            pool: self.pool,
+            depth_left: self.depth_left - 1,
+            visiting: self.visiting
+        }
+    }
+}
+
+impl<'a> Serialize for SerializeValue<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let id = match self.value {
+            Value::Null => return serializer.serialize_none(),
+            Value::Bool(v) => return serializer.serialize_bool(v),
+            Value::Int(v) => return serializer.serialize_i64(v),
+            Value::Float(v) => return serializer.serialize_f64(v),
+            Value::Object(id) => id
+        };
+
+        if !self.visiting.borrow_mut().insert(id) {
+            return Err(ser::Error::custom("Cycle detected"));
+        }
+
+        let ctx = ValueContext::new(&self.value, self.pool);
+        let obj = ctx.as_object_direct().as_any();
+
+        let ret = if let Some(s) = obj.downcast_ref::<String>() {
+            serializer.serialize_str(s)
+        } else if let Some(b) = obj.downcast_ref::<Bytes>() {
+            serializer.serialize_bytes(&b.data)
+        } else if self.depth_left == 0 && (obj.is::<Array>() || obj.is::<Map>()) {
+            Err(ser::Error::custom("Maximum depth exceeded"))
+        } else if let Some(a) = obj.downcast_ref::<Array>() {
+            let elements = a.elements.borrow();
+            let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+            for v in elements.iter() {
+                seq.serialize_element(&self.child(*v))?;
+            }
+            seq.end()
+        } else if let Some(m) = obj.downcast_ref::<Map>() {
+            let entries = m.entries.borrow();
+            let mut map = serializer.serialize_map(Some(entries.len()))?;
+            for (k, v) in entries.iter() {
+                map.serialize_entry(k, &self.child(*v))?;
+            }
+            map.end()
+        } else {
+            Err(ser::Error::custom(format!(
+                "Unsupported object type: {}",
+                ctx.as_object_direct().typename()
+            )))
+        };
+
+        self.visiting.borrow_mut().remove(&id);
+        ret
+    }
+}
+
+/// Deserializes a value graph, allocating strings, byte buffers,
+/// arrays and maps into the object pool of `executor`.
+///
+/// Allocations are checked against the memory limit as they happen, and
+/// `out_of_memory` is set if parsing stopped because of it.
+///
+/// The values allocated so far are not rooted. This is sound because the
+/// executor is borrowed exclusively for the whole parse, so nothing can
+/// collect until the result has been handed back.
+pub struct DeserializeValue<'a> {
+    executor: &'a mut ExecutorImpl,
+    depth_left: u32,
+    out_of_memory: &'a Cell<bool>
+}
+
+impl<'a> DeserializeValue<'a> {
+    pub fn new(
+        executor: &'a mut ExecutorImpl,
+        max_depth: u32,
+        out_of_memory: &'a Cell<bool>
+    ) -> DeserializeValue<'a> {
+        DeserializeValue {
+            executor: executor,
+            depth_left: max_depth,
+            out_of_memory: out_of_memory
+        }
+    }
+
+    fn child<'b>(&'b mut self) -> DeserializeValue<'b> {
+        DeserializeValue {
+            executor: &mut *self.executor,
+            depth_left: self.depth_left - 1,
+            out_of_memory: self.out_of_memory
+        }
+    }
+
+    fn allocated<E: de::Error>(&self, id: Option<usize>) -> Result<Value, E> {
+        match id {
+            Some(id) => Ok(Value::Object(id)),
+            None => {
+                self.out_of_memory.set(true);
+                Err(E::custom("Memory limit exceeded"))
+            }
+        }
+    }
+
+    fn allocate<E: de::Error>(&mut self, obj: Box<Object>) -> Result<Value, E> {
+        let id = exec_state::allocate(self.executor, obj);
+        self.allocated(id)
+    }
+
+    fn allocate_string<E: de::Error>(&mut self, v: String) -> Result<Value, E> {
+        let len = v.len();
+        let id = exec_state::allocate_sized(self.executor, Box::new(v), len);
+        self.allocated(id)
+    }
+
+    fn allocate_bytes<E: de::Error>(&mut self, v: Vec<u8>) -> Result<Value, E> {
+        let id = exec_state::allocate_bytes(self.executor, v);
+        self.allocated(id)
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for DeserializeValue<'a> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for DeserializeValue<'a> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        if v > ::std::i64::MAX as u64 {
+            Err(E::custom(format!("Integer out of range: {}", v)))
+        } else {
+            Ok(Value::Int(v as i64))
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Float(v))
+    }
+
+    fn visit_str<E: de::Error>(mut self, v: &str) -> Result<Value, E> {
+        self.allocate_string(v.to_string())
+    }
+
+    fn visit_string<E: de::Error>(mut self, v: String) -> Result<Value, E> {
+        self.allocate_string(v)
+    }
+
+    fn visit_bytes<E: de::Error>(mut self, v: &[u8]) -> Result<Value, E> {
+        self.allocate_bytes(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(mut self, v: Vec<u8>) -> Result<Value, E> {
+        self.allocate_bytes(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<Value, A::Error> {
+        if self.depth_left == 0 {
+            return Err(de::Error::custom("Maximum depth exceeded"));
+        }
+
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(v) = seq.next_element_seed(self.child())? {
+            elements.push(v);
+        }
+
+        let array = Array::new();
+        *array.elements.borrow_mut() = elements;
+        self.allocate(Box::new(array))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Value, A::Error> {
+        if self.depth_left == 0 {
+            return Err(de::Error::custom("Maximum depth exceeded"));
+        }
+
+        let mut entries = BTreeMap::new();
+        while let Some(k) = map.next_key::<String>()? {
+            let v = map.next_value_seed(self.child())?;
+            entries.insert(k, v);
+        }
+
+        let m = Map::new();
+        *m.entries.borrow_mut() = entries;
+        self.allocate(Box::new(m))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use hexagon_vm_core::executor::ExecutorImpl;
+    use hexagon_vm_core::value::Value;
+    use glue::hexagon_glue_free;
+    use ort::api::*;
+    use ort::last_error::{self, ErrorKind};
+    use ort::test_util::with_executor;
+
+    fn from_json(e: &mut ExecutorImpl, json: &str, max_depth: u32) -> Option<Value> {
+        let mut v = Value::Null;
+        match hexagon_ort_value_from_json_with_depth(&mut v, e, json.as_ptr(), json.len() as u32, max_depth) {
+            0 => Some(v),
+            _ => None
+        }
+    }
+
+    fn to_json(e: &ExecutorImpl, v: &Value, max_depth: u32) -> Option<String> {
+        let s = hexagon_ort_value_to_json_with_depth(v, e, max_depth);
+        if s.is_null() {
+            None
+        } else {
+            Some(unsafe { CString::from_raw(s) }.into_string().unwrap())
+        }
+    }
+
+    fn to_msgpack(e: &ExecutorImpl, v: &Value) -> Vec<u8> {
+        let mut len = 0;
+        let buf = hexagon_ort_value_to_msgpack(&mut len, v, e);
+        assert!(!buf.is_null());
+        let ret = unsafe { ::std::slice::from_raw_parts(buf, len as usize) }.to_vec();
+        unsafe { hexagon_glue_free(buf); }
+        ret
+    }
+
+    #[test]
+    fn json_round_trip() {
+        with_executor(|e| {
+            let json = r#"{"a":[1,2.5,null,true],"b":{"c":"d"}}"#;
+            let v = from_json(e, json, 8).unwrap();
+            assert_eq!(to_json(e, &v, 8).unwrap(), json);
+        });
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        with_executor(|e| {
+            let json = r#"{"a":[1,2.5,null,true],"b":{"c":"d"}}"#;
+            let v = from_json(e, json, 8).unwrap();
+
+            let packed = to_msgpack(e, &v);
+            let mut decoded = Value::Null;
+            assert_eq!(hexagon_ort_value_from_msgpack(&mut decoded, e, packed.as_ptr(), packed.len() as u32), 0);
+            assert_eq!(to_json(e, &decoded, 8).unwrap(), json);
+
+            let mut bytes = Value::Null;
+            assert_eq!(hexagon_ort_value_create_from_bytes(&mut bytes, b"\x00\xff".as_ptr(), 2, e), 0);
+            let packed = to_msgpack(e, &bytes);
+            assert_eq!(hexagon_ort_value_from_msgpack(&mut decoded, e, packed.as_ptr(), packed.len() as u32), 0);
+            assert_eq!(hexagon_ort_value_get_type_ex(&decoded, e), b'Y');
+        });
+    }
+
+    #[test]
+    fn msgpack_trailing_bytes_are_rejected() {
+        with_executor(|e| {
+            let mut packed = to_msgpack(e, &Value::Int(1));
+            packed.push(0xc0);
+            let mut v = Value::Null;
+            assert_eq!(hexagon_ort_value_from_msgpack(&mut v, e, packed.as_ptr(), packed.len() as u32), 1);
+            assert_eq!(last_error::kind(), ErrorKind::Decode);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn depth_limit_is_enforced() {
+        with_executor(|e| {
+            let v = from_json(e, "[[1]]", 2).unwrap();
+            assert!(to_json(e, &v, 2).is_some());
+
+            assert!(to_json(e, &v, 1).is_none());
+            assert_eq!(last_error::kind(), ErrorKind::Encode);
+            assert!(from_json(e, "[[[1]]]", 2).is_none());
+            assert_eq!(last_error::kind(), ErrorKind::Decode);
+            last_error::clear();
+        });
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        with_executor(|e| {
+            let mut a = Value::Null;
+            assert_eq!(hexagon_ort_array_create(&mut a, e), 0);
+            assert_eq!(hexagon_ort_array_push(&a, e, &a), 0);
+
+            assert!(to_json(e, &a, 1000).is_none());
+            assert_eq!(last_error::kind(), ErrorKind::Encode);
+            let mut len = 0;
+            assert!(hexagon_ort_value_to_msgpack(&mut len, &a, e).is_null());
+            last_error::clear();
+        });
+    }
+}
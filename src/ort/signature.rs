@@ -0,0 +1,187 @@
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::value::Value;
+use super::api::hexagon_ort_value_get_type_ex;
+
+/// Argument types declared for a native function.
+///
+/// A signature is a string of type codes, one per argument, using the
+/// codes of `hexagon_ort_value_get_type_ex` plus `.` for any value.
+/// `O` matches any object. A code followed by `?` is optional, and only
+/// optional arguments may come after it. A code followed by `*` matches
+/// any number of trailing arguments and must come last. Null is accepted
+/// for an optional argument, as if it had been left out.
+///
+/// For example, `IF?S*` takes an integer, an optional float and then
+/// any number of strings.
+pub struct Signature {
+    params: Vec<u8>,
+    n_required: usize,
+    variadic: Option<u8>
+}
+
+impl Signature {
+    pub fn parse(s: &str) -> Result<Signature, String> {
+        let s = s.as_bytes();
+        let mut params = Vec::new();
+        let mut n_required = 0;
+        let mut variadic = None;
+
+        let mut i = 0;
+        while i < s.len() {
+            let code = s[i];
+            if !is_type_code(code) {
+                return Err(format!("Unknown type code '{}' at position {}", code as char, i));
+            }
+            if variadic.is_some() {
+                return Err("Variadic argument must come last".to_string());
+            }
+            match s.get(i + 1) {
+                Some(&b'?') => {
+                    params.push(code);
+                    i += 2;
+                },
+                Some(&b'*') => {
+                    variadic = Some(code);
+                    i += 2;
+                },
+                _ => {
+                    if params.len() != n_required {
+                        return Err(format!("Required argument at position {} follows an optional one", i));
+                    }
+                    params.push(code);
+                    n_required += 1;
+                    i += 1;
+                }
+            }
+        }
+
+        Ok(Signature {
+            params: params,
+            n_required: n_required,
+            variadic: variadic
+        })
+    }
+
+    /// Checks `args`, returning a message that describes the first mismatch.
+    pub fn check(&self, e: &ExecutorImpl, args: &[Value]) -> Result<(), String> {
+        if args.len() < self.n_required {
+            return Err(format!(
+                "Expected at least {} arguments, got {}",
+                self.n_required,
+                args.len()
+            ));
+        }
+        if self.variadic.is_none() && args.len() > self.params.len() {
+            return Err(format!(
+                "Expected at most {} arguments, got {}",
+                self.params.len(),
+                args.len()
+            ));
+        }
+
+        for (i, v) in args.iter().enumerate() {
+            let expected = match self.params.get(i) {
+                Some(&c) => c,
+                None => self.variadic.unwrap()
+            };
+            let actual = hexagon_ort_value_get_type_ex(v, e);
+            let omitted = actual == b'N' && i >= self.n_required && i < self.params.len();
+            if !omitted && !matches(expected, actual) {
+                return Err(format!(
+                    "Argument {}: expected {}, got {}",
+                    i,
+                    type_name(expected),
+                    type_name(actual)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn is_type_code(c: u8) -> bool {
+    match c {
+        b'B' | b'I' | b'F' | b'N' | b'S' | b'Y' | b'A' | b'M' | b'P' | b'C' | b'O' | b'.' => true,
+        _ => false
+    }
+}
+
+fn matches(expected: u8, actual: u8) -> bool {
+    match expected {
+        b'.' => true,
+        b'O' => match actual {
+            b'B' | b'I' | b'F' | b'N' => false,
+            _ => true
+        },
+        _ => expected == actual
+    }
+}
+
+fn type_name(c: u8) -> &'static str {
+    match c {
+        b'B' => "bool",
+        b'I' => "int",
+        b'F' => "float",
+        b'N' => "null",
+        b'S' => "string",
+        b'Y' => "bytes",
+        b'A' => "array",
+        b'M' => "map",
+        b'P' => "object proxy",
+        b'C' => "function",
+        b'O' => "object",
+        _ => "any value"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hexagon_vm_core::value::Value;
+    use ort::api::hexagon_ort_value_create_from_string;
+    use ort::test_util::{c_str, with_executor};
+    use super::Signature;
+
+    #[test]
+    fn parse_signatures() {
+        let s = Signature::parse("IF?S*").unwrap();
+        assert_eq!(s.params, b"IF".to_vec());
+        assert_eq!(s.n_required, 1);
+        assert_eq!(s.variadic, Some(b'S'));
+
+        let s = Signature::parse("").unwrap();
+        assert_eq!(s.params.len(), 0);
+        assert_eq!(s.variadic, None);
+
+        assert!(Signature::parse("I?F").is_err());
+        assert!(Signature::parse("I*F").is_err());
+        assert!(Signature::parse("X").is_err());
+        assert!(Signature::parse("?").is_err());
+    }
+
+    #[test]
+    fn check_arguments() {
+        with_executor(|e| {
+            let mut s = Value::Null;
+            assert_eq!(hexagon_ort_value_create_from_string(&mut s, c_str(b"x\0"), e), 0);
+
+            let sig = Signature::parse("IF?S*").unwrap();
+            assert!(sig.check(e, &[Value::Int(1)]).is_ok());
+            assert!(sig.check(e, &[Value::Int(1), Value::Float(2.0)]).is_ok());
+            assert!(sig.check(e, &[Value::Int(1), Value::Float(2.0), s, s]).is_ok());
+            assert!(sig.check(e, &[Value::Int(1), Value::Null, s]).is_ok());
+
+            assert!(sig.check(e, &[]).is_err());
+            assert!(sig.check(e, &[Value::Null]).is_err());
+            assert!(sig.check(e, &[Value::Float(1.0)]).is_err());
+            assert!(sig.check(e, &[Value::Int(1), s]).is_err());
+            assert!(sig.check(e, &[Value::Int(1), Value::Float(2.0), Value::Null]).is_err());
+
+            let sig = Signature::parse("O.").unwrap();
+            assert!(sig.check(e, &[s, Value::Null]).is_ok());
+            assert!(sig.check(e, &[Value::Int(1), Value::Null]).is_err());
+            assert!(sig.check(e, &[s]).is_err());
+            assert!(sig.check(e, &[s, s, s]).is_err());
+        });
+    }
+}
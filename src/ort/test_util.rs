@@ -0,0 +1,38 @@
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use hexagon_vm_core::executor::ExecutorImpl;
+use hexagon_vm_core::value::Value;
+use super::api::*;
+
+pub fn c_str(s: &[u8]) -> *const c_char {
+    s.as_ptr() as *const c_char
+}
+
+pub fn with_executor<F: FnOnce(&mut ExecutorImpl)>(f: F) {
+    let executor = hexagon_ort_executor_create();
+    let e = unsafe { &mut *hexagon_ort_executor_get_impl(&mut *executor) };
+    f(e);
+    unsafe { hexagon_ort_executor_destroy(executor); }
+}
+
+pub fn expect_int(v: Option<Value>, expected: i64) {
+    match v {
+        Some(Value::Int(v)) => assert_eq!(v, expected),
+        _ => panic!("Expected Int({})", expected)
+    }
+}
+
+extern "C" fn count_drop(data: *const ()) {
+    let counter = unsafe { &*(data as *const AtomicUsize) };
+    counter.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Pins a proxy that increments `counter` when it is dropped.
+pub fn pin_counted_proxy(e: &mut ExecutorImpl, counter: &'static AtomicUsize) -> Value {
+    let p = hexagon_ort_object_proxy_create(counter as *const AtomicUsize as *const ());
+    unsafe { hexagon_ort_object_proxy_set_destructor(&mut *p, Some(count_drop)); }
+
+    let mut v = Value::Null;
+    assert_eq!(hexagon_ort_executor_pin_object_proxy(&mut v, e, p), 0);
+    v
+}